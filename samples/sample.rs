@@ -1,78 +1,611 @@
 // Sample Rust file for theme preview
 // Demonstrates syntax highlighting across different Rust constructs
 
-use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::error::Error as StdError;
 use std::fmt::{self, Display};
-use std::sync::{Arc, Mutex};
+use std::fs::{self, File, OpenOptions};
+use std::future::Future;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::task::Poll;
+use std::time::Duration;
+
+use rusqlite::{params, Connection, TransactionBehavior};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{json, Value};
+use tokio::sync::OnceCell;
+use tokio::task::JoinSet;
+use tokio::time::Instant;
 
 // Constants
 const MAX_RETRIES: u32 = 3;
 const API_BASE_URL: &str = "https://api.example.com";
+const RETRY_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_CONCURRENT_FETCHES: usize = 8;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const REQUEST_DEADLINE: Duration = Duration::from_secs(20);
+const BREAKER_FAILURE_THRESHOLD: u32 = 5;
+const BREAKER_COOLDOWN: Duration = Duration::from_secs(30);
+const MAX_NAME_LEN: usize = 64;
+const MAX_EMAIL_LEN: usize = 254;
+const MAX_METADATA_KEYS: usize = 32;
+const MAX_METADATA_BYTES: usize = 4096;
+const DEFAULT_CACHE_CAPACITY: usize = 1024;
+const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(300);
 
 // Type alias
 type Result<T> = std::result::Result<T, AppError>;
-type UserCache = Arc<Mutex<HashMap<u64, User>>>;
 
 // Enum with variants
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+#[repr(u8)]
 pub enum UserRole {
-    Admin,
-    User,
-    Guest,
+    Admin = 0,
+    #[default]
+    User = 1,
+    Guest = 2,
+}
+
+impl UserRole {
+    /// Lowercase name used in serialized forms
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UserRole::Admin => "admin",
+            UserRole::User => "user",
+            UserRole::Guest => "guest",
+        }
+    }
+}
+
+impl Display for UserRole {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for UserRole {
+    type Err = AppError;
+
+    /// Parse a role name, ignoring case
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "admin" => Ok(UserRole::Admin),
+            "user" => Ok(UserRole::User),
+            "guest" => Ok(UserRole::Guest),
+            _ => Err(AppError::parse("role", format!("unknown role '{}'", s))),
+        }
+    }
+}
+
+impl UserRole {
+    /// Permissions every user with this role holds by default
+    pub fn permissions(&self) -> Permissions {
+        match self {
+            UserRole::Admin => Permissions::all(),
+            UserRole::User => Permissions::from_iter([Permission::ReadUser, Permission::WriteUser]),
+            UserRole::Guest => Permissions::from_iter([Permission::ReadUser]),
+        }
+    }
+}
+
+impl TryFrom<u8> for UserRole {
+    type Error = AppError;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            0 => Ok(UserRole::Admin),
+            1 => Ok(UserRole::User),
+            2 => Ok(UserRole::Guest),
+            other => Err(AppError::parse("role", format!("unknown code {}", other))),
+        }
+    }
+}
+
+// Fine-grained capabilities, one bit each
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u32)]
+pub enum Permission {
+    ReadUser = 1 << 0,
+    WriteUser = 1 << 1,
+    DeleteUser = 1 << 2,
+    ManageRoles = 1 << 3,
+    /// Modify records other than one's own
+    ManageUsers = 1 << 4,
+}
+
+impl Permission {
+    pub const ALL: [Permission; 5] = [
+        Permission::ReadUser,
+        Permission::WriteUser,
+        Permission::DeleteUser,
+        Permission::ManageRoles,
+        Permission::ManageUsers,
+    ];
+}
+
+// Set of permissions stored as a bit mask
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
+pub struct Permissions(u32);
+
+impl Permissions {
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub fn all() -> Self {
+        Permission::ALL.into_iter().collect()
+    }
+
+    /// Rebuild a set from `bits`, dropping unknown bits
+    pub fn from_bits(bits: u32) -> Self {
+        Self(bits & Self::all().0)
+    }
+
+    pub fn bits(&self) -> u32 {
+        self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn contains(&self, permission: Permission) -> bool {
+        self.0 & permission as u32 != 0
+    }
+
+    pub fn insert(&mut self, permission: Permission) {
+        self.0 |= permission as u32;
+    }
+
+    pub fn remove(&mut self, permission: Permission) {
+        self.0 &= !(permission as u32);
+    }
+
+    pub fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    pub fn difference(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+}
+
+impl FromIterator<Permission> for Permissions {
+    fn from_iter<I: IntoIterator<Item = Permission>>(iter: I) -> Self {
+        let mut set = Self::empty();
+        for permission in iter {
+            set.insert(permission);
+        }
+        set
+    }
+}
+
+// Message of an error plus the breadcrumbs and cause recorded alongside it
+#[derive(Debug, Clone, Default)]
+pub struct Detail {
+    message: String,
+    context: Vec<String>,
+    // An `Arc` so errors stay `Clone`
+    source: Option<Arc<dyn StdError + Send + Sync>>,
+}
+
+impl Detail {
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// Breadcrumbs from the outermost inwards
+    pub fn context(&self) -> &[String] {
+        &self.context
+    }
+}
+
+impl Display for Detail {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl From<String> for Detail {
+    fn from(message: String) -> Self {
+        Self {
+            message,
+            ..Self::default()
+        }
+    }
+}
+
+impl From<&str> for Detail {
+    fn from(message: &str) -> Self {
+        Self::from(message.to_string())
+    }
+}
+
+impl PartialEq<str> for Detail {
+    fn eq(&self, other: &str) -> bool {
+        self.message == other
+    }
+}
+
+impl PartialEq<&str> for Detail {
+    fn eq(&self, other: &&str) -> bool {
+        self.message == *other
+    }
 }
 
 // Error enum
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum AppError {
-    NotFound(String),
-    Unauthorized,
-    NetworkError(String),
-    ParseError { field: String, message: String },
+    NotFound(Detail),
+    Unauthorized(Detail),
+    NetworkError(Detail),
+    Timeout(Detail),
+    ParseError {
+        field: String,
+        message: Detail,
+    },
+    Conflict(Detail),
+    VersionConflict {
+        expected: u64,
+        actual: u64,
+        detail: Detail,
+    },
+    Storage(Detail),
+    Internal(Detail),
+    Rejected(Detail),
 }
 
 impl Display for AppError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for context in self.detail().context() {
+            write!(f, "{}: ", context)?;
+        }
         match self {
             AppError::NotFound(msg) => write!(f, "Not found: {}", msg),
-            AppError::Unauthorized => write!(f, "Unauthorized access"),
+            AppError::Unauthorized(msg) if msg.message().is_empty() => {
+                write!(f, "Unauthorized access")
+            }
+            AppError::Unauthorized(msg) => write!(f, "Unauthorized access: {}", msg),
             AppError::NetworkError(msg) => write!(f, "Network error: {}", msg),
+            AppError::Timeout(msg) => write!(f, "Timed out: {}", msg),
             AppError::ParseError { field, message } => {
                 write!(f, "Parse error in {}: {}", field, message)
             }
+            AppError::Conflict(msg) => write!(f, "Conflict: {}", msg),
+            AppError::VersionConflict {
+                expected, actual, ..
+            } => {
+                write!(
+                    f,
+                    "Version conflict: expected {}, found {}",
+                    expected, actual
+                )
+            }
+            AppError::Storage(msg) => write!(f, "Storage error: {}", msg),
+            AppError::Internal(msg) => write!(f, "Internal error: {}", msg),
+            AppError::Rejected(msg) => write!(f, "Request rejected: {}", msg),
+        }
+    }
+}
+
+impl StdError for AppError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        let source = self.detail().source.as_ref()?;
+        Some(source.as_ref())
+    }
+}
+
+impl From<io::Error> for AppError {
+    fn from(err: io::Error) -> Self {
+        AppError::Storage(err.to_string().into()).with_source(err)
+    }
+}
+
+impl From<rusqlite::Error> for AppError {
+    fn from(err: rusqlite::Error) -> Self {
+        let message = Detail::from(err.to_string());
+        let error = match err.sqlite_error_code() {
+            Some(rusqlite::ErrorCode::ConstraintViolation) => AppError::Conflict(message),
+            _ => AppError::Storage(message),
+        };
+        error.with_source(err)
+    }
+}
+
+impl AppError {
+    /// Build a parse error for the given field
+    pub fn parse(field: impl Into<String>, message: impl Into<String>) -> Self {
+        AppError::ParseError {
+            field: field.into(),
+            message: Detail::from(message.into()),
+        }
+    }
+
+    /// Build a version conflict between the expected and the stored version
+    pub fn version_conflict(expected: u64, actual: u64) -> Self {
+        AppError::VersionConflict {
+            expected,
+            actual,
+            detail: Detail::default(),
+        }
+    }
+
+    /// Message, breadcrumbs and cause shared by every variant
+    pub fn detail(&self) -> &Detail {
+        match self {
+            AppError::NotFound(detail)
+            | AppError::Unauthorized(detail)
+            | AppError::NetworkError(detail)
+            | AppError::Timeout(detail)
+            | AppError::ParseError {
+                message: detail, ..
+            }
+            | AppError::Conflict(detail)
+            | AppError::VersionConflict { detail, .. }
+            | AppError::Storage(detail)
+            | AppError::Internal(detail)
+            | AppError::Rejected(detail) => detail,
+        }
+    }
+
+    fn detail_mut(&mut self) -> &mut Detail {
+        match self {
+            AppError::NotFound(detail)
+            | AppError::Unauthorized(detail)
+            | AppError::NetworkError(detail)
+            | AppError::Timeout(detail)
+            | AppError::ParseError {
+                message: detail, ..
+            }
+            | AppError::Conflict(detail)
+            | AppError::VersionConflict { detail, .. }
+            | AppError::Storage(detail)
+            | AppError::Internal(detail)
+            | AppError::Rejected(detail) => detail,
+        }
+    }
+
+    /// Add a breadcrumb, printed before the existing ones by `Display`
+    pub fn context(mut self, context: impl Into<String>) -> Self {
+        self.detail_mut().context.insert(0, context.into());
+        self
+    }
+
+    /// Keep `source` as the cause reported by `Error::source`
+    pub fn with_source(mut self, source: impl StdError + Send + Sync + 'static) -> Self {
+        self.detail_mut().source = Some(Arc::new(source));
+        self
+    }
+
+    /// Whether retrying the operation might succeed
+    pub fn is_transient(&self) -> bool {
+        matches!(self, AppError::NetworkError(_) | AppError::Timeout(_))
+    }
+
+    /// Stable snake_case name of the variant
+    pub fn kind(&self) -> &'static str {
+        match self {
+            AppError::NotFound(_) => "not_found",
+            AppError::Unauthorized(_) => "unauthorized",
+            AppError::NetworkError(_) => "network_error",
+            AppError::Timeout(_) => "timeout",
+            AppError::ParseError { .. } => "parse_error",
+            AppError::Conflict(_) => "conflict",
+            AppError::VersionConflict { .. } => "version_conflict",
+            AppError::Storage(_) => "storage",
+            AppError::Internal(_) => "internal",
+            AppError::Rejected(_) => "rejected",
+        }
+    }
+
+    /// Machine-readable code of the variant; codes are never reused
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::NotFound(_) => "E001",
+            AppError::Unauthorized(_) => "E002",
+            AppError::NetworkError(_) => "E003",
+            AppError::Timeout(_) => "E004",
+            AppError::ParseError { .. } => "E005",
+            AppError::Conflict(_) => "E006",
+            AppError::VersionConflict { .. } => "E007",
+            AppError::Storage(_) => "E008",
+            AppError::Internal(_) => "E009",
+            AppError::Rejected(_) => "E010",
+        }
+    }
+}
+
+// Breadcrumbs on results, e.g. `repo.save(user).context("importing users")?`
+pub trait ResultExt<T> {
+    fn context(self, context: impl Into<String>) -> Result<T>;
+
+    /// Like `context`, but only builds the message on failure
+    fn with_context<C: Into<String>>(self, context: impl FnOnce() -> C) -> Result<T>;
+}
+
+impl<T> ResultExt<T> for Result<T> {
+    fn context(self, context: impl Into<String>) -> Result<T> {
+        self.map_err(|err| err.context(context))
+    }
+
+    fn with_context<C: Into<String>>(self, context: impl FnOnce() -> C) -> Result<T> {
+        self.map_err(|err| err.context(context()))
+    }
+}
+
+// Wire representation of `AppError` for API responses
+#[cfg(feature = "serde")]
+#[derive(Serialize, Deserialize)]
+struct ErrorBody {
+    kind: String,
+    #[serde(default)]
+    code: String,
+    message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    field: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expected: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    actual: Option<u64>,
+    /// Breadcrumbs from the outermost inwards; sources are not sent
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    context: Vec<String>,
+}
+
+#[cfg(feature = "serde")]
+impl From<&AppError> for ErrorBody {
+    fn from(err: &AppError) -> Self {
+        let field = match err {
+            AppError::ParseError { field, .. } => Some(field.clone()),
+            _ => None,
+        };
+        let (expected, actual) = match err {
+            AppError::VersionConflict {
+                expected, actual, ..
+            } => (Some(*expected), Some(*actual)),
+            _ => (None, None),
+        };
+        let detail = err.detail();
+        Self {
+            kind: err.kind().to_string(),
+            code: err.code().to_string(),
+            message: detail.message.clone(),
+            field,
+            expected,
+            actual,
+            context: detail.context.clone(),
         }
     }
 }
 
-impl std::error::Error for AppError {}
+#[cfg(feature = "serde")]
+impl TryFrom<ErrorBody> for AppError {
+    type Error = String;
+
+    fn try_from(body: ErrorBody) -> std::result::Result<Self, String> {
+        let detail = Detail {
+            message: body.message,
+            context: body.context,
+            source: None,
+        };
+        let error = match body.kind.as_str() {
+            "not_found" => AppError::NotFound(detail),
+            "unauthorized" => AppError::Unauthorized(detail),
+            "network_error" => AppError::NetworkError(detail),
+            "timeout" => AppError::Timeout(detail),
+            "parse_error" => AppError::ParseError {
+                field: body.field.unwrap_or_default(),
+                message: detail,
+            },
+            "conflict" => AppError::Conflict(detail),
+            "version_conflict" => AppError::VersionConflict {
+                expected: body.expected.unwrap_or_default(),
+                actual: body.actual.unwrap_or_default(),
+                detail,
+            },
+            "storage" => AppError::Storage(detail),
+            "internal" => AppError::Internal(detail),
+            "rejected" => AppError::Rejected(detail),
+            other => return Err(format!("unknown error kind '{}'", other)),
+        };
+        // Bodies from before codes existed carry none
+        if !body.code.is_empty() && body.code != error.code() {
+            return Err(format!("code '{}' does not match its kind", body.code));
+        }
+        Ok(error)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl Serialize for AppError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        ErrorBody::from(self).serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for AppError {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let body = ErrorBody::deserialize(deserializer)?;
+        AppError::try_from(body).map_err(serde::de::Error::custom)
+    }
+}
 
 // Struct with derive macros
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct User {
     pub id: u64,
     pub name: String,
     pub email: String,
+    /// Records written before roles existed load as `UserRole::User`
+    #[cfg_attr(feature = "serde", serde(default))]
     pub role: UserRole,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
     metadata: Option<HashMap<String, String>>,
+    /// Permissions granted on top of the role's
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Permissions::is_empty")
+    )]
+    grants: Permissions,
+    /// Permissions withheld even if the role or a grant includes them
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Permissions::is_empty")
+    )]
+    denials: Permissions,
 }
 
 impl User {
+    /// Start building a user
+    pub fn builder() -> UserBuilder {
+        UserBuilder::default()
+    }
+
     /// Create a new user with the given details
     pub fn new(id: u64, name: impl Into<String>, email: impl Into<String>) -> Self {
-        Self {
-            id,
-            name: name.into(),
-            email: email.into(),
-            role: UserRole::User,
-            metadata: None,
-        }
+        Self::builder()
+            .id(id)
+            .name(name)
+            .email(email)
+            .build_unchecked()
+    }
+
+    /// Create a user after trimming and validating name and email
+    pub fn try_new(id: u64, name: impl Into<String>, email: impl Into<String>) -> Result<Self> {
+        Self::builder().id(id).name(name).email(email).build()
+    }
+
+    /// Check the name, email and metadata rules enforced by `try_new`
+    pub fn validate(&self) -> Result<()> {
+        validate_name(&self.name)?;
+        validate_email(&self.email)?;
+        validate_metadata(self.metadata_iter())
     }
 
     /// Create an admin user
     pub fn admin(id: u64, name: impl Into<String>, email: impl Into<String>) -> Self {
-        Self {
-            role: UserRole::Admin,
-            ..Self::new(id, name, email)
-        }
+        Self::builder()
+            .id(id)
+            .name(name)
+            .email(email)
+            .role(UserRole::Admin)
+            .build_unchecked()
     }
 
     /// Check if user is admin
@@ -81,204 +614,3973 @@ impl User {
         matches!(self.role, UserRole::Admin)
     }
 
-    /// Add metadata to user
+    /// Grant a permission beyond the user's role
+    pub fn grant(mut self, permission: Permission) -> Self {
+        self.denials.remove(permission);
+        self.grants.insert(permission);
+        self
+    }
+
+    /// Withhold a permission regardless of role
+    pub fn deny(mut self, permission: Permission) -> Self {
+        self.grants.remove(permission);
+        self.denials.insert(permission);
+        self
+    }
+
+    /// Role permissions plus grants, minus denials
+    pub fn permissions(&self) -> Permissions {
+        self.role
+            .permissions()
+            .union(self.grants)
+            .difference(self.denials)
+    }
+
+    /// Check whether the user holds a permission
+    pub fn can(&self, permission: Permission) -> bool {
+        self.permissions().contains(permission)
+    }
+
+    /// Add metadata to user, without checking the size limits
     pub fn with_metadata(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
         self.metadata
             .get_or_insert_with(HashMap::new)
             .insert(key.into(), value.into());
         self
     }
-}
 
-impl Display for User {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "User({}: {} <{}>)", self.id, self.name, self.email)
+    /// Insert or replace a metadata entry, keeping the metadata within limits
+    pub fn set_metadata(&mut self, key: impl Into<String>, value: impl Into<String>) -> Result<()> {
+        let (key, value) = (key.into(), value.into());
+        let others = self.metadata_iter().filter(|(k, _)| *k != key);
+        validate_metadata(others.chain([(key.as_str(), value.as_str())]))?;
+
+        self.metadata
+            .get_or_insert_with(HashMap::new)
+            .insert(key, value);
+        Ok(())
     }
-}
 
-// Trait definition
-pub trait Repository<T> {
-    fn find_by_id(&self, id: u64) -> Result<Option<T>>;
-    fn save(&mut self, item: T) -> Result<()>;
-    fn delete(&mut self, id: u64) -> Result<bool>;
-}
+    pub fn get_metadata(&self, key: &str) -> Option<&str> {
+        self.metadata.as_ref()?.get(key).map(String::as_str)
+    }
 
-// Generic struct
-pub struct InMemoryRepository<T: Clone> {
-    storage: HashMap<u64, T>,
-    id_counter: u64,
-}
+    /// Parse a metadata value, reporting failures against `key`
+    pub fn get_metadata_as<T>(&self, key: &str) -> Result<Option<T>>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        self.get_metadata(key)
+            .map(|value| {
+                value
+                    .parse()
+                    .map_err(|e: T::Err| AppError::parse(key, e.to_string()))
+            })
+            .transpose()
+    }
 
-impl<T: Clone> InMemoryRepository<T> {
-    pub fn new() -> Self {
-        Self {
-            storage: HashMap::new(),
-            id_counter: 0,
+    pub fn remove_metadata(&mut self, key: &str) -> Option<String> {
+        let metadata = self.metadata.as_mut()?;
+        let removed = metadata.remove(key);
+        if metadata.is_empty() {
+            self.metadata = None;
         }
+        removed
     }
 
-    pub fn len(&self) -> usize {
-        self.storage.len()
+    pub fn metadata_iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.metadata
+            .iter()
+            .flatten()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
     }
 
-    pub fn is_empty(&self) -> bool {
-        self.storage.is_empty()
+    /// Encode the user as JSON, the inverse of `from_json`
+    #[cfg(feature = "serde")]
+    pub fn to_json(&self) -> Value {
+        // Plain fields and string-keyed maps always have a JSON form
+        serde_json::to_value(self).expect("users serialize to JSON")
     }
-}
 
-impl<T: Clone> Default for InMemoryRepository<T> {
-    fn default() -> Self {
-        Self::new()
+    /// Decode a user from its JSON representation
+    #[cfg(feature = "serde")]
+    pub fn from_json(value: &Value) -> Result<Self> {
+        User::deserialize(value).map_err(|e| AppError::parse("user", e.to_string()).with_source(e))
     }
-}
 
-// Trait implementation
-impl Repository<User> for InMemoryRepository<User> {
-    fn find_by_id(&self, id: u64) -> Result<Option<User>> {
-        Ok(self.storage.get(&id).cloned())
+    /// Encode the user in the same format the `serde` feature derives
+    #[cfg(not(feature = "serde"))]
+    pub fn to_json(&self) -> Value {
+        let mut value = json!({
+            "id": self.id,
+            "name": self.name,
+            "email": self.email,
+            "role": self.role.as_str(),
+        });
+        if let Some(metadata) = &self.metadata {
+            value["metadata"] = json!(metadata);
+        }
+        for (name, permissions) in [("grants", self.grants), ("denials", self.denials)] {
+            if !permissions.is_empty() {
+                value[name] = json!(permissions.bits());
+            }
+        }
+        value
     }
 
-    fn save(&mut self, item: User) -> Result<()> {
-        self.storage.insert(item.id, item);
-        Ok(())
-    }
+    /// Decode a user written in the `serde` feature's format
+    #[cfg(not(feature = "serde"))]
+    pub fn from_json(value: &Value) -> Result<Self> {
+        let string_field = |name: &str| -> Result<String> {
+            value
+                .get(name)
+                .and_then(Value::as_str)
+                .map(str::to_string)
+                .ok_or_else(|| AppError::parse(name, "expected a string"))
+        };
 
-    fn delete(&mut self, id: u64) -> Result<bool> {
-        Ok(self.storage.remove(&id).is_some())
+        let id = value
+            .get("id")
+            .and_then(Value::as_u64)
+            .ok_or_else(|| AppError::parse("id", "expected an unsigned integer"))?;
+
+        let role = match value.get("role").and_then(Value::as_str) {
+            Some(name) => name.parse()?,
+            None => UserRole::default(),
+        };
+
+        let permissions = |name: &str| -> Result<Permissions> {
+            match value.get(name) {
+                None => Ok(Permissions::empty()),
+                Some(bits) => bits
+                    .as_u64()
+                    .and_then(|bits| u32::try_from(bits).ok())
+                    .map(Permissions::from_bits)
+                    .ok_or_else(|| AppError::parse(name, "expected a permission mask")),
+            }
+        };
+
+        let metadata = match value.get("metadata") {
+            None | Some(Value::Null) => None,
+            Some(Value::Object(map)) => Some(
+                map.iter()
+                    .map(|(key, value)| match value.as_str() {
+                        Some(value) => Ok((key.clone(), value.to_string())),
+                        None => Err(AppError::parse(
+                            "metadata",
+                            format!("value of '{}' is not a string", key),
+                        )),
+                    })
+                    .collect::<Result<HashMap<_, _>>>()?,
+            ),
+            Some(_) => return Err(AppError::parse("metadata", "expected an object")),
+        };
+
+        Ok(Self {
+            id,
+            name: string_field("name")?,
+            email: string_field("email")?,
+            role,
+            metadata,
+            grants: permissions("grants")?,
+            denials: permissions("denials")?,
+        })
     }
 }
 
-// Async function with lifetimes
-pub async fn fetch_user<'a>(
-    cache: &'a UserCache,
+// Builder for `User` with a validating `build`
+#[derive(Debug, Clone, Default)]
+pub struct UserBuilder {
     id: u64,
-) -> Result<Option<User>> {
-    // Try cache first
-    {
-        let cache_guard = cache.lock().map_err(|_| {
-            AppError::NetworkError("Cache lock poisoned".to_string())
-        })?;
-        
-        if let Some(user) = cache_guard.get(&id) {
-            return Ok(Some(user.clone()));
-        }
+    name: String,
+    email: String,
+    role: Option<UserRole>,
+    metadata: HashMap<String, String>,
+}
+
+impl UserBuilder {
+    pub fn id(mut self, id: u64) -> Self {
+        self.id = id;
+        self
+    }
+
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        self
     }
 
-    // Simulate network fetch
-    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    pub fn email(mut self, email: impl Into<String>) -> Self {
+        self.email = email.into();
+        self
+    }
 
-    // Mock response
-    let user = User::new(id, format!("User {}", id), format!("user{}@example.com", id));
+    /// Set the role, `UserRole::User` if never called
+    pub fn role(mut self, role: UserRole) -> Self {
+        self.role = Some(role);
+        self
+    }
 
-    // Update cache
-    {
-        let mut cache_guard = cache.lock().map_err(|_| {
-            AppError::NetworkError("Cache lock poisoned".to_string())
-        })?;
-        cache_guard.insert(id, user.clone());
+    pub fn metadata(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.metadata.insert(key.into(), value.into());
+        self
+    }
+
+    /// Add every entry of `map`, overwriting existing keys
+    pub fn metadata_map(mut self, map: HashMap<String, String>) -> Self {
+        self.metadata.extend(map);
+        self
+    }
+
+    /// Trim name and email, then validate them
+    pub fn build(mut self) -> Result<User> {
+        self.name = self.name.trim().to_string();
+        self.email = self.email.trim().to_string();
+        let user = self.build_unchecked();
+        user.validate()?;
+        Ok(user)
     }
 
-    Ok(Some(user))
+    fn build_unchecked(self) -> User {
+        User {
+            id: self.id,
+            name: self.name,
+            email: self.email,
+            role: self.role.unwrap_or(UserRole::User),
+            metadata: (!self.metadata.is_empty()).then_some(self.metadata),
+            grants: Permissions::empty(),
+            denials: Permissions::empty(),
+        }
+    }
 }
 
-// Macro usage
-macro_rules! create_users {
-    ($($id:expr => $name:expr),+ $(,)?) => {
-        vec![
-            $(
-                User::new($id, $name, format!("{}@example.com", $name.to_lowercase()))
-            ),+
-        ]
+fn validate_name(name: &str) -> Result<()> {
+    let invalid = |message: &str| Err(AppError::parse("name", message));
+
+    if name.trim().is_empty() {
+        return invalid("must not be empty");
+    }
+    if name.trim() != name {
+        return invalid("has leading or trailing whitespace");
+    }
+    if name.chars().count() > MAX_NAME_LEN {
+        return invalid(&format!("is longer than {} characters", MAX_NAME_LEN));
+    }
+    if name.chars().any(char::is_control) {
+        return invalid("contains control characters");
+    }
+    Ok(())
+}
+
+fn validate_metadata<'a>(entries: impl Iterator<Item = (&'a str, &'a str)>) -> Result<()> {
+    let (mut keys, mut bytes) = (0, 0);
+    for (key, value) in entries {
+        keys += 1;
+        bytes += key.len() + value.len();
+    }
+
+    if keys > MAX_METADATA_KEYS {
+        let message = format!("has more than {} keys", MAX_METADATA_KEYS);
+        return Err(AppError::parse("metadata", message));
+    }
+    if bytes > MAX_METADATA_BYTES {
+        let message = format!("is larger than {} bytes", MAX_METADATA_BYTES);
+        return Err(AppError::parse("metadata", message));
+    }
+    Ok(())
+}
+
+fn validate_email(email: &str) -> Result<()> {
+    let invalid = |message: &str| Err(AppError::parse("email", message));
+
+    if email.len() > MAX_EMAIL_LEN {
+        return invalid("is too long");
+    }
+    if email.chars().any(|c| c.is_control() || c.is_whitespace()) {
+        return invalid("contains whitespace or control characters");
+    }
+    let Some((local, domain)) = email.split_once('@') else {
+        return invalid("is missing an '@'");
     };
+
+    let local_ok = !local.is_empty()
+        && local.len() <= 64
+        && !local.starts_with('.')
+        && !local.ends_with('.')
+        && !local.contains("..")
+        && local
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+/=?^_`{|}~.-".contains(c));
+    if !local_ok {
+        return invalid("has an invalid local part");
+    }
+
+    let labels: Vec<&str> = domain.split('.').collect();
+    let domain_ok = labels.len() >= 2
+        && labels.iter().all(|label| {
+            !label.is_empty()
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        });
+    if !domain_ok {
+        return invalid("has an invalid domain");
+    }
+    Ok(())
+}
+
+impl Display for User {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "User({}: {} <{}>)", self.id, self.name, self.email)
+    }
+}
+
+// Identity trait for stored entities
+pub trait Entity {
+    fn id(&self) -> u64;
+
+    /// Secondary key that no two stored entities may share
+    fn unique_key(&self) -> Option<String> {
+        None
+    }
+}
+
+// Entities whose id can be assigned by the repository
+pub trait HasId: Entity {
+    fn set_id(&mut self, id: u64);
+}
+
+impl Entity for User {
+    fn id(&self) -> u64 {
+        self.id
+    }
+
+    fn unique_key(&self) -> Option<String> {
+        Some(normalize_email(&self.email))
+    }
+}
+
+/// Canonical form of an email used for comparisons
+pub fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+impl HasId for User {
+    fn set_id(&mut self, id: u64) {
+        self.id = id;
+    }
+}
+
+// Trait definition
+pub trait Repository<T> {
+    fn find_by_id(&self, id: u64) -> Result<Option<T>>;
+    fn save(&mut self, item: T) -> Result<()>;
+    fn delete(&mut self, id: u64) -> Result<bool>;
+
+    /// Version of the stored item, or 0 when the id was never stored
+    ///
+    /// Every save and delete bumps the version, so a caller can detect concurrent edits.
+    /// Deleted ids keep their version, so a recreated record never reuses an old one.
+    fn version_of(&self, id: u64) -> Result<u64>;
+
+    /// Save only if the stored version is still `expected`, returning the new version
+    fn save_if_version(&mut self, item: T, expected: u64) -> Result<u64>;
+
+    /// All items, ordered by id
+    fn find_all(&self) -> Result<Vec<T>>;
+
+    /// Items matching the predicate, ordered by id
+    fn find_where<F: Fn(&T) -> bool>(&self, predicate: F) -> Result<Vec<T>> {
+        let mut items = self.find_all()?;
+        items.retain(|item| predicate(item));
+        Ok(items)
+    }
+
+    fn count_where<F: Fn(&T) -> bool>(&self, predicate: F) -> Result<usize> {
+        Ok(self.find_where(predicate)?.len())
+    }
+
+    /// One page of items ordered by id, numbering pages from zero
+    fn list(&self, page: usize, page_size: usize) -> Result<Page<T>> {
+        check_page_size(page_size)?;
+        let items = self.find_all()?;
+        let total = items.len();
+        let page_items = items
+            .into_iter()
+            .skip(page.saturating_mul(page_size))
+            .take(page_size)
+            .collect();
+        Ok(Page::new(page_items, page, page_size, total))
+    }
+}
+
+// Paginated result
+#[derive(Debug, Clone, PartialEq)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub page: usize,
+    pub page_size: usize,
+    pub total: usize,
+    /// Page to request next, or `None` on the last page
+    pub next_cursor: Option<usize>,
+}
+
+impl<T> Page<T> {
+    pub fn new(items: Vec<T>, page: usize, page_size: usize, total: usize) -> Self {
+        let seen = page.saturating_add(1).saturating_mul(page_size);
+        Self {
+            items,
+            page,
+            page_size,
+            total,
+            next_cursor: (seen < total).then(|| page + 1),
+        }
+    }
+}
+
+fn check_page_size(page_size: usize) -> Result<()> {
+    if page_size == 0 {
+        return Err(AppError::parse("page_size", "must be greater than zero"));
+    }
+    Ok(())
+}
+
+fn check_version(expected: u64, actual: u64) -> Result<()> {
+    if expected != actual {
+        return Err(AppError::version_conflict(expected, actual));
+    }
+    Ok(())
+}
+
+// Generic struct
+pub struct InMemoryRepository<T: Clone> {
+    storage: HashMap<u64, T>,
+    unique_index: HashMap<String, u64>,
+    versions: HashMap<u64, u64>,
+    id_counter: u64,
+}
+
+impl<T: Clone> InMemoryRepository<T> {
+    pub fn new() -> Self {
+        Self {
+            storage: HashMap::new(),
+            unique_index: HashMap::new(),
+            versions: HashMap::new(),
+            id_counter: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.storage.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.storage.is_empty()
+    }
+}
+
+impl<T: Clone + HasId> InMemoryRepository<T> {
+    /// Load existing entities, seeding the id counter past the highest id
+    pub fn from_items(items: impl IntoIterator<Item = T>) -> Result<Self> {
+        let mut repo = Self::new();
+        for item in items {
+            repo.save(item)?;
+        }
+        Ok(repo)
+    }
+
+    /// Store an entity under a freshly allocated id and return that id
+    pub fn insert_new(&mut self, mut item: T) -> Result<u64> {
+        let id = self
+            .id_counter
+            .checked_add(1)
+            .ok_or_else(|| AppError::Storage("no ids left to allocate".into()))?;
+        self.id_counter = id;
+        item.set_id(id);
+        self.save(item)?;
+        Ok(id)
+    }
+
+    /// Move the id counter to at least `floor` and past every stored id
+    ///
+    /// The counter never moves backwards, so ids freed by deletes are not reused.
+    pub fn reseed(&mut self, floor: u64) {
+        let highest = self.storage.keys().copied().max().unwrap_or(0);
+        self.id_counter = self.id_counter.max(floor).max(highest);
+    }
+}
+
+impl<T: Entity + Clone> InMemoryRepository<T> {
+    /// Look up an entity by its unique key
+    pub fn find_by_unique_key(&self, key: &str) -> Result<Option<T>> {
+        match self.unique_index.get(key) {
+            Some(id) => self.find_by_id(*id),
+            None => Ok(None),
+        }
+    }
+
+    /// Save `item`, checking the stored version first when `expected` is given
+    fn store(&mut self, item: T, expected: Option<u64>) -> Result<u64> {
+        let id = item.id();
+        let key = item.unique_key();
+        let current = self.versions.get(&id).copied().unwrap_or(0);
+
+        if let Some(expected) = expected {
+            check_version(expected, current)?;
+        }
+        if let Some(key) = &key {
+            match self.unique_index.get(key) {
+                Some(&owner) if owner != id => {
+                    let message = format!("'{}' is already taken by {}", key, owner);
+                    return Err(AppError::Conflict(message.into()));
+                }
+                _ => {}
+            }
+        }
+
+        // Release the previous key when an update changes it
+        if let Some(old_key) = self.storage.get(&id).and_then(Entity::unique_key) {
+            self.unique_index.remove(&old_key);
+        }
+        if let Some(key) = key {
+            self.unique_index.insert(key, id);
+        }
+
+        self.id_counter = self.id_counter.max(id);
+        self.storage.insert(id, item);
+        self.versions.insert(id, current + 1);
+        Ok(current + 1)
+    }
+
+    /// Put back a record and its version exactly as they were before a write
+    fn restore(&mut self, id: u64, previous: Option<T>, version: u64) -> Result<()> {
+        match previous {
+            Some(previous) => {
+                self.store(previous, None)?;
+            }
+            None => {
+                self.delete(id)?;
+            }
+        }
+        if version == 0 {
+            self.versions.remove(&id);
+        } else {
+            self.versions.insert(id, version);
+        }
+        Ok(())
+    }
+
+    /// Versions of deleted ids, ordered by id
+    fn tombstones(&self) -> Vec<(u64, u64)> {
+        let mut tombstones: Vec<(u64, u64)> = self
+            .versions
+            .iter()
+            .filter(|(id, _)| !self.storage.contains_key(id))
+            .map(|(&id, &version)| (id, version))
+            .collect();
+        tombstones.sort_unstable();
+        tombstones
+    }
+}
+
+impl InMemoryRepository<User> {
+    /// Look up a user by email, ignoring case
+    pub fn find_by_email(&self, email: &str) -> Result<Option<User>> {
+        self.find_by_unique_key(&normalize_email(email))
+    }
+}
+
+impl<T: Clone> Default for InMemoryRepository<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Trait implementation
+impl<T: Entity + Clone> Repository<T> for InMemoryRepository<T> {
+    fn find_by_id(&self, id: u64) -> Result<Option<T>> {
+        Ok(self.storage.get(&id).cloned())
+    }
+
+    fn save(&mut self, item: T) -> Result<()> {
+        self.store(item, None).map(|_| ())
+    }
+
+    fn delete(&mut self, id: u64) -> Result<bool> {
+        let Some(item) = self.storage.remove(&id) else {
+            return Ok(false);
+        };
+        if let Some(key) = item.unique_key() {
+            self.unique_index.remove(&key);
+        }
+        // Keep the version as a tombstone so a recreated id continues from it
+        *self.versions.entry(id).or_default() += 1;
+        Ok(true)
+    }
+
+    fn version_of(&self, id: u64) -> Result<u64> {
+        Ok(self.versions.get(&id).copied().unwrap_or(0))
+    }
+
+    fn save_if_version(&mut self, item: T, expected: u64) -> Result<u64> {
+        self.store(item, Some(expected))
+    }
+
+    fn find_all(&self) -> Result<Vec<T>> {
+        self.find_where(|_| true)
+    }
+
+    fn find_where<F: Fn(&T) -> bool>(&self, predicate: F) -> Result<Vec<T>> {
+        let mut items: Vec<T> = self
+            .storage
+            .values()
+            .filter(|item| predicate(item))
+            .cloned()
+            .collect();
+        items.sort_by_key(Entity::id);
+        Ok(items)
+    }
+
+    fn count_where<F: Fn(&T) -> bool>(&self, predicate: F) -> Result<usize> {
+        Ok(self.storage.values().filter(|item| predicate(item)).count())
+    }
+
+    fn list(&self, page: usize, page_size: usize) -> Result<Page<T>> {
+        check_page_size(page_size)?;
+        let mut ids: Vec<u64> = self.storage.keys().copied().collect();
+        ids.sort_unstable();
+        let items = ids
+            .iter()
+            .skip(page.saturating_mul(page_size))
+            .take(page_size)
+            .map(|id| self.storage[id].clone())
+            .collect();
+        Ok(Page::new(items, page, page_size, ids.len()))
+    }
+}
+
+// In-memory repository behind a read-write lock, shared between tasks
+pub struct SharedRepository<T: Clone> {
+    inner: Arc<RwLock<InMemoryRepository<T>>>,
+}
+
+impl<T: Clone> SharedRepository<T> {
+    pub fn new() -> Self {
+        Self::from(InMemoryRepository::new())
+    }
+
+    pub async fn len(&self) -> Result<usize> {
+        Ok(self.read()?.len())
+    }
+
+    pub async fn is_empty(&self) -> Result<bool> {
+        Ok(self.read()?.is_empty())
+    }
+
+    fn read(&self) -> Result<RwLockReadGuard<'_, InMemoryRepository<T>>> {
+        self.inner
+            .read()
+            .map_err(|_| AppError::Internal("repository lock poisoned".into()))
+    }
+
+    fn write(&self) -> Result<RwLockWriteGuard<'_, InMemoryRepository<T>>> {
+        self.inner
+            .write()
+            .map_err(|_| AppError::Internal("repository lock poisoned".into()))
+    }
+}
+
+// Async mirror of `Repository`; the lock is never held across an await
+impl<T: Entity + Clone> SharedRepository<T> {
+    pub async fn find_by_id(&self, id: u64) -> Result<Option<T>> {
+        self.read()?.find_by_id(id)
+    }
+
+    pub async fn save(&self, item: T) -> Result<()> {
+        self.write()?.save(item)
+    }
+
+    pub async fn delete(&self, id: u64) -> Result<bool> {
+        self.write()?.delete(id)
+    }
+
+    pub async fn version_of(&self, id: u64) -> Result<u64> {
+        self.read()?.version_of(id)
+    }
+
+    pub async fn save_if_version(&self, item: T, expected: u64) -> Result<u64> {
+        self.write()?.save_if_version(item, expected)
+    }
+
+    pub async fn find_all(&self) -> Result<Vec<T>> {
+        self.read()?.find_all()
+    }
+
+    pub async fn find_where<F: Fn(&T) -> bool>(&self, predicate: F) -> Result<Vec<T>> {
+        self.read()?.find_where(predicate)
+    }
+
+    pub async fn count_where<F: Fn(&T) -> bool>(&self, predicate: F) -> Result<usize> {
+        self.read()?.count_where(predicate)
+    }
+
+    pub async fn list(&self, page: usize, page_size: usize) -> Result<Page<T>> {
+        self.read()?.list(page, page_size)
+    }
+}
+
+impl<T: Clone> Clone for SharedRepository<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<T: Clone> Default for SharedRepository<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Clone> From<InMemoryRepository<T>> for SharedRepository<T> {
+    fn from(repo: InMemoryRepository<T>) -> Self {
+        Self {
+            inner: Arc::new(RwLock::new(repo)),
+        }
+    }
+}
+
+// Append-only JSON Lines log replayed into memory on open
+pub struct FileRepository {
+    path: PathBuf,
+    log: File,
+    inner: InMemoryRepository<User>,
+}
+
+impl FileRepository {
+    /// Open the log at `path`, creating it if missing, and rebuild state from it
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut inner = InMemoryRepository::new();
+
+        match File::open(&path) {
+            Ok(file) => {
+                for (index, line) in BufReader::new(file).lines().enumerate() {
+                    let line = line?;
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    Self::replay(&mut inner, &line).map_err(|err| {
+                        AppError::parse(format!("line {}", index + 1), err.to_string())
+                            .with_source(err)
+                    })?;
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+
+        let log = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(Self { path, log, inner })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Look up a user by email, ignoring case
+    pub fn find_by_email(&self, email: &str) -> Result<Option<User>> {
+        self.inner.find_by_email(email)
+    }
+
+    /// Rewrite the log as a snapshot holding one entry per live user or deleted id
+    pub fn compact(&mut self) -> Result<()> {
+        let snapshot_path = self.path.with_extension("snapshot");
+        {
+            let mut snapshot = File::create(&snapshot_path)?;
+            for user in self.inner.find_all()? {
+                let version = self.inner.version_of(user.id)?;
+                writeln!(snapshot, "{}", Self::save_entry(&user, version))?;
+            }
+            for (id, version) in self.inner.tombstones() {
+                writeln!(snapshot, "{}", Self::delete_entry(id, version))?;
+            }
+            snapshot.sync_all()?;
+        }
+
+        fs::rename(&snapshot_path, &self.path)?;
+        self.log = OpenOptions::new().append(true).open(&self.path)?;
+        Ok(())
+    }
+
+    fn replay(inner: &mut InMemoryRepository<User>, line: &str) -> Result<()> {
+        let entry: Value = serde_json::from_str(line)
+            .map_err(|e| AppError::parse("entry", e.to_string()).with_source(e))?;
+
+        match entry.get("op").and_then(Value::as_str) {
+            Some("save") => {
+                let user = entry
+                    .get("user")
+                    .ok_or_else(|| AppError::parse("user", "missing field"))?;
+                let user = User::from_json(user)?;
+                let id = user.id;
+                inner.save(user)?;
+
+                // Entries written before versioning replay as one bump per save
+                if let Some(version) = entry.get("version").and_then(Value::as_u64) {
+                    inner.versions.insert(id, version);
+                }
+                Ok(())
+            }
+            Some("delete") => {
+                let id = entry
+                    .get("id")
+                    .and_then(Value::as_u64)
+                    .ok_or_else(|| AppError::parse("id", "expected an unsigned integer"))?;
+                inner.delete(id)?;
+
+                // Entries written before tombstones only drop the record
+                if let Some(version) = entry.get("version").and_then(Value::as_u64) {
+                    inner.versions.insert(id, version);
+                }
+                Ok(())
+            }
+            _ => Err(AppError::parse("op", "expected \"save\" or \"delete\"")),
+        }
+    }
+
+    fn save_entry(user: &User, version: u64) -> Value {
+        json!({ "op": "save", "version": version, "user": user.to_json() })
+    }
+
+    fn delete_entry(id: u64, version: u64) -> Value {
+        json!({ "op": "delete", "version": version, "id": id })
+    }
+
+    /// Save in memory, then log the entry, undoing the memory write if logging fails
+    fn store(&mut self, item: User, expected: Option<u64>) -> Result<u64> {
+        validate_metadata(item.metadata_iter())?;
+        let previous = self.inner.find_by_id(item.id)?;
+        let previous_version = self.inner.version_of(item.id)?;
+        let version = self.inner.store(item.clone(), expected)?;
+
+        if let Err(err) = self.append(&Self::save_entry(&item, version)) {
+            // Keep memory in line with what reached the disk
+            self.inner.restore(item.id, previous, previous_version)?;
+            return Err(err);
+        }
+        Ok(version)
+    }
+
+    fn append(&mut self, entry: &Value) -> Result<()> {
+        writeln!(self.log, "{}", entry)?;
+        self.log.sync_data()?;
+        Ok(())
+    }
 }
 
-// Main function
-#[tokio::main]
-async fn main() -> Result<()> {
-    // Create users using macro
-    let users = create_users![
-        1 => "Alice",
-        2 => "Bob",
-        3 => "Charlie",
-    ];
+impl Repository<User> for FileRepository {
+    fn find_by_id(&self, id: u64) -> Result<Option<User>> {
+        self.inner.find_by_id(id)
+    }
+
+    fn save(&mut self, item: User) -> Result<()> {
+        self.store(item, None).map(|_| ())
+    }
+
+    fn delete(&mut self, id: u64) -> Result<bool> {
+        let Some(previous) = self.inner.find_by_id(id)? else {
+            return Ok(false);
+        };
+        let previous_version = self.inner.version_of(id)?;
+        self.inner.delete(id)?;
+
+        let version = self.inner.version_of(id)?;
+        if let Err(err) = self.append(&Self::delete_entry(id, version)) {
+            self.inner.restore(id, Some(previous), previous_version)?;
+            return Err(err);
+        }
+        Ok(true)
+    }
+
+    fn version_of(&self, id: u64) -> Result<u64> {
+        self.inner.version_of(id)
+    }
+
+    fn save_if_version(&mut self, item: User, expected: u64) -> Result<u64> {
+        self.store(item, Some(expected))
+    }
+
+    fn find_all(&self) -> Result<Vec<User>> {
+        self.inner.find_all()
+    }
+
+    fn find_where<F: Fn(&User) -> bool>(&self, predicate: F) -> Result<Vec<User>> {
+        self.inner.find_where(predicate)
+    }
+
+    fn count_where<F: Fn(&User) -> bool>(&self, predicate: F) -> Result<usize> {
+        self.inner.count_where(predicate)
+    }
+
+    fn list(&self, page: usize, page_size: usize) -> Result<Page<User>> {
+        self.inner.list(page, page_size)
+    }
+}
+
+// Schema migrations, applied in order and tracked in `PRAGMA user_version`
+const SQLITE_MIGRATIONS: &[&str] = &[
+    "CREATE TABLE users (
+        id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL,
+        email TEXT NOT NULL,
+        email_key TEXT NOT NULL,
+        role TEXT NOT NULL
+    );
+    CREATE UNIQUE INDEX users_email_key ON users (email_key);
+    CREATE TABLE user_metadata (
+        user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+        key TEXT NOT NULL,
+        value TEXT NOT NULL,
+        PRIMARY KEY (user_id, key)
+    );",
+    "ALTER TABLE users ADD COLUMN grants INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE users ADD COLUMN denials INTEGER NOT NULL DEFAULT 0;",
+    "ALTER TABLE users ADD COLUMN version INTEGER NOT NULL DEFAULT 1;",
+    "CREATE TABLE user_tombstones (
+        id INTEGER PRIMARY KEY,
+        version INTEGER NOT NULL
+    );",
+];
+
+const SQLITE_SELECT_USERS: &str = "SELECT id, name, email, role, grants, denials FROM users";
+
+// SQLite-backed user storage
+pub struct SqliteRepository {
+    conn: Connection,
+}
+
+impl SqliteRepository {
+    /// Open the database at `path`, applying pending migrations
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::from_connection(Connection::open(path)?)
+    }
+
+    pub fn open_in_memory() -> Result<Self> {
+        Self::from_connection(Connection::open_in_memory()?)
+    }
+
+    fn from_connection(mut conn: Connection) -> Result<Self> {
+        conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+        Self::migrate(&mut conn)?;
+        Ok(Self { conn })
+    }
+
+    fn migrate(conn: &mut Connection) -> Result<()> {
+        let applied = Self::read_schema_version(conn)?;
+        for (index, migration) in SQLITE_MIGRATIONS.iter().enumerate().skip(applied) {
+            let tx = conn.transaction()?;
+            tx.execute_batch(migration)?;
+            tx.pragma_update(None, "user_version", index as u32 + 1)?;
+            tx.commit()?;
+        }
+        Ok(())
+    }
+
+    fn read_schema_version(conn: &Connection) -> Result<usize> {
+        let version: u32 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        Ok(version as usize)
+    }
+
+    /// Number of migrations applied to the database
+    pub fn schema_version(&self) -> Result<usize> {
+        Self::read_schema_version(&self.conn)
+    }
+
+    /// Look up a user by email, ignoring case
+    pub fn find_by_email(&self, email: &str) -> Result<Option<User>> {
+        self.query_users("WHERE email_key = ?1", params![normalize_email(email)])
+            .map(|users| users.into_iter().next())
+    }
+
+    /// Run `SQLITE_SELECT_USERS` followed by `clause`
+    fn query_users(&self, clause: &str, params: &[&dyn rusqlite::ToSql]) -> Result<Vec<User>> {
+        let mut stmt = self
+            .conn
+            .prepare(&format!("{} {}", SQLITE_SELECT_USERS, clause))?;
+        let rows: Vec<(User, String)> = stmt
+            .query_map(params, |row| {
+                let (name, email, role): (String, String, String) =
+                    (row.get(1)?, row.get(2)?, row.get(3)?);
+                let mut user = User::new(row.get(0)?, name, email);
+                user.grants = Permissions::from_bits(row.get(4)?);
+                user.denials = Permissions::from_bits(row.get(5)?);
+                Ok((user, role))
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+
+        rows.into_iter()
+            .map(|(mut user, role)| {
+                user.role = role.parse()?;
+                user.metadata = self.load_metadata(user.id)?;
+                Ok(user)
+            })
+            .collect()
+    }
+
+    fn load_metadata(&self, id: u64) -> Result<Option<HashMap<String, String>>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT key, value FROM user_metadata WHERE user_id = ?1")?;
+        let metadata = stmt
+            .query_map(params![id], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<HashMap<String, String>>>()?;
+        Ok((!metadata.is_empty()).then_some(metadata))
+    }
+
+    /// SQLite integers are signed, so ids above `i64::MAX` cannot be stored
+    fn sql_id(id: u64) -> Result<i64> {
+        let message = || format!("{} is too large to store", id);
+        i64::try_from(id).map_err(|_| AppError::parse("id", message()))
+    }
+
+    /// Version of the live row, or of the tombstone left by its last delete
+    fn read_version(conn: &Connection, id: u64) -> Result<u64> {
+        let id = Self::sql_id(id)?;
+        let version: Option<u64> = conn.query_row(
+            "SELECT MAX(version) FROM (
+                SELECT version FROM users WHERE id = ?1
+                UNION ALL
+                SELECT version FROM user_tombstones WHERE id = ?1
+            )",
+            params![id],
+            |row| row.get(0),
+        )?;
+        Ok(version.unwrap_or(0))
+    }
+
+    /// Upsert `item` and its metadata, checking the stored version first when `expected` is given
+    fn store(&mut self, item: User, expected: Option<u64>) -> Result<u64> {
+        validate_metadata(item.metadata_iter())?;
+        // Take the write lock up front so the version cannot move between check and write
+        let tx = self
+            .conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let current = Self::read_version(&tx, item.id)?;
+        if let Some(expected) = expected {
+            check_version(expected, current)?;
+        }
+
+        tx.execute(
+            "INSERT INTO users (id, name, email, email_key, role, grants, denials, version)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+             ON CONFLICT (id) DO UPDATE SET
+                name = excluded.name,
+                email = excluded.email,
+                email_key = excluded.email_key,
+                role = excluded.role,
+                grants = excluded.grants,
+                denials = excluded.denials,
+                version = excluded.version",
+            params![
+                item.id,
+                item.name,
+                item.email,
+                normalize_email(&item.email),
+                item.role.as_str(),
+                item.grants.bits(),
+                item.denials.bits(),
+                current + 1
+            ],
+        )?;
+        tx.execute(
+            "DELETE FROM user_metadata WHERE user_id = ?1",
+            params![item.id],
+        )?;
+        for (key, value) in item.metadata.iter().flatten() {
+            tx.execute(
+                "INSERT INTO user_metadata (user_id, key, value) VALUES (?1, ?2, ?3)",
+                params![item.id, key, value],
+            )?;
+        }
+        tx.commit()?;
+        Ok(current + 1)
+    }
+}
+
+impl Repository<User> for SqliteRepository {
+    fn find_by_id(&self, id: u64) -> Result<Option<User>> {
+        self.query_users("WHERE id = ?1", params![Self::sql_id(id)?])
+            .map(|users| users.into_iter().next())
+    }
+
+    fn save(&mut self, item: User) -> Result<()> {
+        self.store(item, None).map(|_| ())
+    }
+
+    fn delete(&mut self, id: u64) -> Result<bool> {
+        let tx = self
+            .conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let current = Self::read_version(&tx, id)?;
+        let removed = tx.execute("DELETE FROM users WHERE id = ?1", params![id])?;
+        if removed > 0 {
+            // Keep the version so a recreated id continues from it
+            tx.execute(
+                "INSERT INTO user_tombstones (id, version) VALUES (?1, ?2)
+                 ON CONFLICT (id) DO UPDATE SET version = excluded.version",
+                params![id, current + 1],
+            )?;
+        }
+        tx.commit()?;
+        Ok(removed > 0)
+    }
+
+    fn version_of(&self, id: u64) -> Result<u64> {
+        Self::read_version(&self.conn, id)
+    }
+
+    fn save_if_version(&mut self, item: User, expected: u64) -> Result<u64> {
+        self.store(item, Some(expected))
+    }
+
+    fn find_all(&self) -> Result<Vec<User>> {
+        self.query_users("ORDER BY id", params![])
+    }
+
+    fn list(&self, page: usize, page_size: usize) -> Result<Page<User>> {
+        check_page_size(page_size)?;
+        let total: usize = self
+            .conn
+            .query_row("SELECT COUNT(*) FROM users", params![], |row| row.get(0))?;
+        // Past the end of SQLite's integer range every page is empty anyway
+        let limit = i64::try_from(page_size).unwrap_or(i64::MAX);
+        let offset = i64::try_from(page.saturating_mul(page_size)).unwrap_or(i64::MAX);
+        let items = self.query_users("ORDER BY id LIMIT ?1 OFFSET ?2", params![limit, offset])?;
+        Ok(Page::new(items, page, page_size, total))
+    }
+}
+
+// Repository wrapper checking the acting user's permissions on each call
+//
+// With the default role permissions, guests are read-only, users may edit
+// only their own record and never their access, and admins may do anything.
+pub struct AuthorizedRepository<R: Repository<User>> {
+    inner: R,
+}
+
+impl<R: Repository<User>> AuthorizedRepository<R> {
+    pub fn new(inner: R) -> Self {
+        Self { inner }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    pub fn find_by_id(&self, actor: &User, id: u64) -> Result<Option<User>> {
+        authorize(actor, Permission::ReadUser)?;
+        self.inner.find_by_id(id)
+    }
+
+    pub fn find_all(&self, actor: &User) -> Result<Vec<User>> {
+        authorize(actor, Permission::ReadUser)?;
+        self.inner.find_all()
+    }
+
+    /// Save a user; changing a role, grant or denial also needs `ManageRoles`
+    pub fn save(&mut self, actor: &User, item: User) -> Result<()> {
+        self.authorize_save(actor, &item)?;
+        self.inner.save(item)
+    }
+
+    /// Save a user only if its stored version is still `expected`
+    pub fn save_if_version(&mut self, actor: &User, item: User, expected: u64) -> Result<u64> {
+        self.authorize_save(actor, &item)?;
+        self.inner.save_if_version(item, expected)
+    }
+
+    pub fn delete(&mut self, actor: &User, id: u64) -> Result<bool> {
+        authorize(actor, Permission::DeleteUser)?;
+        authorize_target(actor, id)?;
+        self.inner.delete(id)
+    }
+
+    fn authorize_save(&self, actor: &User, item: &User) -> Result<()> {
+        authorize(actor, Permission::WriteUser)?;
+        authorize_target(actor, item.id)?;
+
+        let changes_access = match self.inner.find_by_id(item.id)? {
+            Some(current) => {
+                current.role != item.role
+                    || current.grants != item.grants
+                    || current.denials != item.denials
+            }
+            None => item.permissions() != UserRole::User.permissions(),
+        };
+        if changes_access {
+            authorize(actor, Permission::ManageRoles)?;
+        }
+        Ok(())
+    }
+}
+
+fn authorize(actor: &User, permission: Permission) -> Result<()> {
+    if actor.can(permission) {
+        Ok(())
+    } else {
+        let message = format!("user {} lacks {:?}", actor.id, permission);
+        Err(AppError::Unauthorized(message.into()))
+    }
+}
+
+/// Acting on someone else's record needs `ManageUsers`
+fn authorize_target(actor: &User, target_id: u64) -> Result<()> {
+    if actor.id == target_id {
+        Ok(())
+    } else {
+        authorize(actor, Permission::ManageUsers)
+    }
+}
+
+// HTTP client for the user API
+#[derive(Debug, Clone)]
+pub struct HttpUserClient {
+    http: reqwest::Client,
+    base_url: String,
+    max_retries: u32,
+    backoff: Duration,
+    timeout: Duration,
+    deadline: Duration,
+    max_concurrency: usize,
+}
+
+impl HttpUserClient {
+    pub fn new(base_url: impl Into<String>) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+            max_retries: MAX_RETRIES,
+            backoff: RETRY_BACKOFF,
+            timeout: REQUEST_TIMEOUT,
+            deadline: REQUEST_DEADLINE,
+            max_concurrency: MAX_CONCURRENT_FETCHES,
+        }
+    }
+
+    /// Set how many times transient failures are retried
+    pub fn with_max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Set the delay before the first retry, doubled on each further attempt
+    pub fn with_backoff(mut self, backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Set how long a single attempt may take before it counts as failed
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Set the limit for a whole lookup, spanning every attempt and backoff
+    pub fn with_deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    /// Set how many requests a batch fetch keeps open at once
+    pub fn with_max_concurrency(mut self, max_concurrency: usize) -> Self {
+        self.max_concurrency = max_concurrency.max(1);
+        self
+    }
+
+    /// GET `{base_url}/users/{id}`, retrying transient failures until the deadline
+    pub async fn get_user(&self, id: u64) -> Result<User> {
+        let attempts = async {
+            let mut attempt = 0;
+            loop {
+                let result = tokio::time::timeout(self.timeout, self.try_get_user(id))
+                    .await
+                    .unwrap_or_else(|_| {
+                        let message = format!("user {} took longer than {:?}", id, self.timeout);
+                        Err(AppError::Timeout(message.into()))
+                    });
+                match result {
+                    Err(err) if err.is_transient() && attempt < self.max_retries => {
+                        let backoff = self.backoff.saturating_mul(2u32.saturating_pow(attempt));
+                        tokio::time::sleep(backoff).await;
+                        attempt += 1;
+                    }
+                    result => return result,
+                }
+            }
+        };
+
+        tokio::time::timeout(self.deadline, attempts)
+            .await
+            .unwrap_or_else(|_| {
+                let message = format!("user {} not loaded within {:?}", id, self.deadline);
+                Err(AppError::Timeout(message.into()))
+            })
+    }
+
+    async fn try_get_user(&self, id: u64) -> Result<User> {
+        let url = format!("{}/users/{}", self.base_url, id);
+        let response = self
+            .http
+            .get(&url)
+            .send()
+            .await
+            .map_err(|e| AppError::NetworkError(e.to_string().into()).with_source(e))?;
+
+        match response.status().as_u16() {
+            200..=299 => {}
+            401 => {
+                let message = format!("{} returned HTTP 401", url);
+                return Err(AppError::Unauthorized(message.into()));
+            }
+            404 => return Err(AppError::NotFound(format!("user {}", id).into())),
+            // Server trouble, timeouts and rate limiting may clear up on a retry
+            status @ (408 | 429 | 500..=599) => {
+                let message = format!("{} returned HTTP {}", url, status);
+                return Err(AppError::NetworkError(message.into()));
+            }
+            status => {
+                let message = format!("{} returned HTTP {}", url, status);
+                return Err(AppError::Rejected(message.into()));
+            }
+        }
+
+        let body = response
+            .text()
+            .await
+            .map_err(|e| AppError::NetworkError(e.to_string().into()).with_source(e))?;
+        let value: Value = serde_json::from_str(&body)
+            .map_err(|e| AppError::parse("body", e.to_string()).with_source(e))?;
+        User::from_json(&value)
+    }
+}
+
+impl Default for HttpUserClient {
+    fn default() -> Self {
+        Self::new(API_BASE_URL)
+    }
+}
+
+// Async trait for user backends
+pub trait UserSource: Sync {
+    /// Load a user, returning `None` if the backend has no such id
+    fn fetch(&self, id: u64) -> impl Future<Output = Result<Option<User>>> + Send;
+
+    /// Load several users, with one result per id; fetches one at a time by default
+    fn fetch_many(
+        &self,
+        ids: &[u64],
+    ) -> impl Future<Output = HashMap<u64, Result<Option<User>>>> + Send {
+        async move {
+            let mut results = HashMap::new();
+            for &id in ids {
+                results.insert(id, self.fetch(id).await);
+            }
+            results
+        }
+    }
+}
+
+impl UserSource for HttpUserClient {
+    async fn fetch(&self, id: u64) -> Result<Option<User>> {
+        match self.get_user(id).await {
+            Ok(user) => Ok(Some(user)),
+            Err(AppError::NotFound(_)) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Issue requests in parallel, keeping at most `max_concurrency` in flight
+    async fn fetch_many(&self, ids: &[u64]) -> HashMap<u64, Result<Option<User>>> {
+        let mut pending = ids.iter().copied();
+        let mut tasks = JoinSet::new();
+        let spawn = |tasks: &mut JoinSet<_>, id: u64| {
+            let client = self.clone();
+            tasks.spawn(async move { (id, client.fetch(id).await) });
+        };
+        for id in pending.by_ref().take(self.max_concurrency) {
+            spawn(&mut tasks, id);
+        }
+
+        let mut results = HashMap::new();
+        while let Some(joined) = tasks.join_next().await {
+            // A task only fails if the fetch panicked, so surface that panic here
+            let (id, result) =
+                joined.unwrap_or_else(|err| std::panic::resume_unwind(err.into_panic()));
+            results.insert(id, result);
+            if let Some(id) = pending.next() {
+                spawn(&mut tasks, id);
+            }
+        }
+        results
+    }
+}
+
+impl UserSource for InMemoryRepository<User> {
+    async fn fetch(&self, id: u64) -> Result<Option<User>> {
+        self.find_by_id(id)
+    }
+}
+
+impl<S: UserSource + Send> UserSource for Arc<S> {
+    fn fetch(&self, id: u64) -> impl Future<Output = Result<Option<User>>> + Send {
+        (**self).fetch(id)
+    }
+
+    fn fetch_many(
+        &self,
+        ids: &[u64],
+    ) -> impl Future<Output = HashMap<u64, Result<Option<User>>>> + Send {
+        (**self).fetch_many(ids)
+    }
+}
+
+impl UserSource for SharedRepository<User> {
+    async fn fetch(&self, id: u64) -> Result<Option<User>> {
+        self.find_by_id(id).await
+    }
+}
+
+// Test double with canned users and a call counter
+#[derive(Debug, Clone, Default)]
+pub struct MockUserSource {
+    users: HashMap<u64, User>,
+    failure: Option<String>,
+    failures_left: Arc<AtomicUsize>,
+    delay: Duration,
+    calls: Arc<AtomicUsize>,
+}
+
+impl MockUserSource {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_user(mut self, user: User) -> Self {
+        self.users.insert(user.id, user);
+        self
+    }
+
+    /// Make every fetch fail with a network error
+    pub fn failing(mut self, message: impl Into<String>) -> Self {
+        self.failure = Some(message.into());
+        self
+    }
+
+    /// Make the next `count` fetches fail with a network error, shared between clones
+    pub fn failing_first(self, count: usize) -> Self {
+        self.failures_left.store(count, Ordering::SeqCst);
+        self
+    }
+
+    /// Simulate a round trip taking `delay`
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// Number of fetches issued so far, shared between clones
+    pub fn calls(&self) -> usize {
+        self.calls.load(Ordering::SeqCst)
+    }
+}
+
+impl UserSource for MockUserSource {
+    async fn fetch(&self, id: u64) -> Result<Option<User>> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        if !self.delay.is_zero() {
+            tokio::time::sleep(self.delay).await;
+        }
+        let take_failure = |left: usize| left.checked_sub(1);
+        let injected = self
+            .failures_left
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, take_failure)
+            .is_ok();
+        if injected {
+            return Err(AppError::NetworkError("injected failure".into()));
+        }
+        match &self.failure {
+            Some(message) => Err(AppError::NetworkError(message.as_str().into())),
+            None => Ok(self.users.get(&id).cloned()),
+        }
+    }
+}
+
+// Source of the current time, swappable so expiry can be tested
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+// Clock that only moves when told to, shared between clones
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    start: Instant,
+    elapsed_nanos: Arc<AtomicU64>,
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            elapsed_nanos: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        let nanos = u64::try_from(by.as_nanos()).unwrap_or(u64::MAX);
+        self.elapsed_nanos.fetch_add(nanos, Ordering::SeqCst);
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.start + Duration::from_nanos(self.elapsed_nanos.load(Ordering::SeqCst))
+    }
+}
+
+// Counters describing how well the cache is doing
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Entries dropped to make room, not counting expired ones
+    pub evictions: u64,
+}
+
+// Bounded user cache with per-entry expiry and least-recently-used eviction
+#[derive(Clone)]
+pub struct UserCache {
+    state: Arc<Mutex<CacheState>>,
+    clock: Arc<dyn Clock>,
+    capacity: usize,
+    ttl: Duration,
+    /// How long to remember ids the backend does not have; `None` disables it
+    negative_ttl: Option<Duration>,
+    /// How long past expiry a user may be served while it is refreshed
+    stale_grace: Option<Duration>,
+    /// Told about background refreshes that fail; logs to stderr by default
+    refresh_observer: RefreshObserver,
+    /// Upper bound on a load from the backend, whatever the source
+    fetch_deadline: Duration,
+}
+
+#[derive(Default)]
+struct CacheState {
+    entries: HashMap<u64, CacheEntry>,
+    /// Ids keyed by their last use, least recent first
+    recency: BTreeMap<u64, u64>,
+    /// Ids ordered by expiry, soonest first
+    expiries: BTreeSet<(Instant, u64)>,
+    /// Fetches currently running, keyed by id, so concurrent misses share one
+    inflight: HashMap<u64, Flight>,
+    /// Bumped on every access to order entries by recency
+    tick: u64,
+    stats: CacheStats,
+}
+
+/// Outcome of one backend fetch, shared by every caller waiting on it
+type Flight = Arc<OnceCell<Result<Option<User>>>>;
+
+type RefreshObserver = Arc<dyn Fn(u64, &AppError) + Send + Sync>;
+
+enum Lookup {
+    Hit(Option<User>),
+    /// An expired user, plus the refresh to run unless one is already going
+    Stale(User, Option<Flight>),
+    /// A new fetch for the caller to run
+    Miss(Flight),
+    /// A fetch another caller already started
+    Join(Flight),
+}
+
+struct CacheEntry {
+    /// `None` remembers that the backend had no such user
+    user: Option<User>,
+    expires_at: Instant,
+    last_used: u64,
+}
+
+impl UserCache {
+    /// Cache holding at most `capacity` users, each for `ttl` after insertion
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            state: Arc::default(),
+            clock: Arc::new(SystemClock),
+            capacity,
+            ttl,
+            negative_ttl: None,
+            stale_grace: None,
+            refresh_observer: Arc::new(|id, err| {
+                eprintln!("Background refresh of user {} failed: {}", id, err)
+            }),
+            fetch_deadline: REQUEST_DEADLINE,
+        }
+    }
+
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Remember missing ids for `ttl`, usually much shorter than the entry TTL
+    pub fn with_negative_ttl(mut self, ttl: Duration) -> Self {
+        self.negative_ttl = Some(ttl);
+        self
+    }
+
+    /// Keep users up to `grace` past expiry so `fetch_user_shared` can serve them while refreshing
+    pub fn with_stale_while_revalidate(mut self, grace: Duration) -> Self {
+        self.stale_grace = Some(grace);
+        self
+    }
+
+    /// Call `observer` with the id and error instead of logging failed background refreshes
+    pub fn on_refresh_error(
+        mut self,
+        observer: impl Fn(u64, &AppError) + Send + Sync + 'static,
+    ) -> Self {
+        self.refresh_observer = Arc::new(observer);
+        self
+    }
+
+    /// Give up on a backend load after `deadline`, failing with a timeout
+    pub fn with_fetch_deadline(mut self, deadline: Duration) -> Self {
+        self.fetch_deadline = deadline;
+        self
+    }
+
+    /// Look up a live entry, marking it as recently used
+    ///
+    /// A remembered miss reads as `None`, the same as an uncached id.
+    pub fn get(&self, id: u64) -> Result<Option<User>> {
+        let now = self.clock.now();
+        Ok(self.lock()?.get(id, now, self.grace()).flatten())
+    }
+
+    /// Return a live entry, or the fetch for `id` that a miss should await
+    fn lookup(&self, id: u64) -> Result<Lookup> {
+        let now = self.clock.now();
+        Ok(self.lock()?.lookup(id, now, self.grace()))
+    }
+
+    /// Look up several ids under one lock, in the order given
+    fn lookup_many(&self, ids: &[u64]) -> Result<Vec<Lookup>> {
+        let now = self.clock.now();
+        let mut state = self.lock()?;
+        Ok(ids
+            .iter()
+            .map(|&id| state.lookup(id, now, self.grace()))
+            .collect())
+    }
+
+    fn deadline_error(&self, id: u64) -> AppError {
+        let message = format!("user {} not loaded within {:?}", id, self.fetch_deadline);
+        AppError::Timeout(message.into())
+    }
+
+    fn grace(&self) -> Duration {
+        self.stale_grace.unwrap_or_default()
+    }
+
+    /// Store a user, evicting the least recently used entry when full
+    pub fn insert(&self, user: User) -> Result<()> {
+        self.store(user.id, Some(user), self.ttl)
+    }
+
+    /// Drop any cached copy of `id` and remember it is missing, if negative caching is enabled
+    pub fn insert_missing(&self, id: u64) -> Result<()> {
+        match self.negative_ttl {
+            Some(ttl) => self.store(id, None, ttl),
+            None => self.invalidate(id).map(|_| ()),
+        }
+    }
+
+    fn store(&self, id: u64, user: Option<User>, ttl: Duration) -> Result<()> {
+        if self.capacity == 0 {
+            return Ok(());
+        }
+        let now = self.clock.now();
+        let mut state = self.lock()?;
+
+        if !state.entries.contains_key(&id) && state.entries.len() >= self.capacity {
+            // Expired entries go first and do not count as evictions
+            while let Some(&(expires_at, expired)) = state.expiries.first() {
+                if expires_at > now {
+                    break;
+                }
+                state.remove(expired);
+            }
+            if state.entries.len() >= self.capacity {
+                if let Some((_, oldest)) = state.recency.pop_first() {
+                    state.remove(oldest);
+                    state.stats.evictions += 1;
+                }
+            }
+        }
+
+        state.insert(id, user, now + ttl);
+        Ok(())
+    }
+
+    /// Drop one entry, returning whether it was cached
+    pub fn invalidate(&self, id: u64) -> Result<bool> {
+        Ok(self.lock()?.remove(id).is_some())
+    }
+
+    pub fn clear(&self) -> Result<()> {
+        let mut state = self.lock()?;
+        state.entries.clear();
+        state.recency.clear();
+        state.expiries.clear();
+        Ok(())
+    }
+
+    /// Number of stored entries, including expired ones not yet dropped
+    pub fn len(&self) -> Result<usize> {
+        Ok(self.lock()?.entries.len())
+    }
+
+    pub fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    pub fn stats(&self) -> Result<CacheStats> {
+        Ok(self.lock()?.stats)
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, CacheState>> {
+        self.state
+            .lock()
+            .map_err(|_| AppError::Internal("cache lock poisoned".into()))
+    }
+}
+
+impl Default for UserCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_CACHE_CAPACITY, DEFAULT_CACHE_TTL)
+    }
+}
+
+impl CacheState {
+    /// `Some(None)` is a live negative entry; plain `None` is a miss
+    ///
+    /// Expired users are kept for `grace` so they can still be served stale.
+    fn get(&mut self, id: u64, now: Instant, grace: Duration) -> Option<Option<User>> {
+        self.tick += 1;
+        match self.entries.get(&id) {
+            Some(entry) if entry.expires_at > now => {
+                let user = entry.user.clone();
+                self.touch(id);
+                self.stats.hits += 1;
+                Some(user)
+            }
+            Some(entry) => {
+                if entry.user.is_none() || entry.expires_at + grace <= now {
+                    self.remove(id);
+                }
+                self.stats.misses += 1;
+                None
+            }
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    fn lookup(&mut self, id: u64, now: Instant, grace: Duration) -> Lookup {
+        if let Some(user) = self.get(id, now, grace) {
+            return Lookup::Hit(user);
+        }
+        let stale = self.stale(id, now, grace);
+        match (stale, self.inflight.entry(id)) {
+            // At most one refresh per id: reuse the in-flight slot
+            (Some(user), Entry::Occupied(_)) => Lookup::Stale(user, None),
+            (Some(user), Entry::Vacant(slot)) => {
+                Lookup::Stale(user, Some(Arc::clone(slot.insert(Flight::default()))))
+            }
+            (None, Entry::Occupied(flight)) => Lookup::Join(Arc::clone(flight.get())),
+            (None, Entry::Vacant(slot)) => Lookup::Miss(Arc::clone(slot.insert(Flight::default()))),
+        }
+    }
+
+    /// An expired user that is still within `grace`
+    fn stale(&mut self, id: u64, now: Instant, grace: Duration) -> Option<User> {
+        let entry = self.entries.get(&id)?;
+        if entry.expires_at + grace <= now {
+            return None;
+        }
+        let user = entry.user.clone();
+        self.touch(id);
+        user
+    }
+
+    fn insert(&mut self, id: u64, user: Option<User>, expires_at: Instant) {
+        self.tick += 1;
+        self.remove(id);
+        self.recency.insert(self.tick, id);
+        self.expiries.insert((expires_at, id));
+        let entry = CacheEntry {
+            user,
+            expires_at,
+            last_used: self.tick,
+        };
+        self.entries.insert(id, entry);
+    }
+
+    /// Mark an entry as used at the current tick
+    fn touch(&mut self, id: u64) {
+        if let Some(entry) = self.entries.get_mut(&id) {
+            self.recency.remove(&entry.last_used);
+            self.recency.insert(self.tick, id);
+            entry.last_used = self.tick;
+        }
+    }
+
+    fn remove(&mut self, id: u64) -> Option<CacheEntry> {
+        let entry = self.entries.remove(&id)?;
+        self.recency.remove(&entry.last_used);
+        self.expiries.remove(&(entry.expires_at, id));
+        Some(entry)
+    }
+}
+
+// Clears a flight from the in-flight map once it settles or every waiter gives up
+struct FlightGuard<'a> {
+    cache: &'a UserCache,
+    id: u64,
+    flight: Flight,
+}
+
+impl Drop for FlightGuard<'_> {
+    fn drop(&mut self) {
+        let Ok(mut state) = self.cache.state.lock() else {
+            return;
+        };
+        let ours = state
+            .inflight
+            .get(&self.id)
+            .is_some_and(|flight| Arc::ptr_eq(flight, &self.flight));
+        // Only the map and this guard hold the flight when nobody else is waiting
+        let abandoned = Arc::strong_count(&self.flight) == 2;
+        if ours && (self.flight.initialized() || abandoned) {
+            state.inflight.remove(&self.id);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Calls pass through and failures are counted
+    Closed,
+    /// Calls fail fast until the cool-down has passed
+    Open,
+    /// One probe call decides whether to close or reopen
+    HalfOpen,
+}
+
+type TransitionObserver = Arc<dyn Fn(CircuitState, CircuitState) + Send + Sync>;
+
+// Source wrapper that stops calling a failing backend for a while
+//
+// Only transient errors count as failures; a missing user is a healthy answer.
+#[derive(Clone)]
+pub struct CircuitBreaker<S> {
+    source: S,
+    state: Arc<Mutex<BreakerState>>,
+    clock: Arc<dyn Clock>,
+    failure_threshold: u32,
+    cooldown: Duration,
+    observer: Option<TransitionObserver>,
+}
+
+struct BreakerState {
+    circuit: CircuitState,
+    /// Consecutive failures seen while closed
+    failures: u32,
+    opened_at: Option<Instant>,
+    probing: bool,
+}
+
+impl<S> CircuitBreaker<S> {
+    pub fn new(source: S) -> Self {
+        Self {
+            source,
+            state: Arc::new(Mutex::new(BreakerState {
+                circuit: CircuitState::Closed,
+                failures: 0,
+                opened_at: None,
+                probing: false,
+            })),
+            clock: Arc::new(SystemClock),
+            failure_threshold: BREAKER_FAILURE_THRESHOLD,
+            cooldown: BREAKER_COOLDOWN,
+            observer: None,
+        }
+    }
+
+    /// Set how many consecutive failures open the circuit
+    pub fn with_failure_threshold(mut self, failure_threshold: u32) -> Self {
+        self.failure_threshold = failure_threshold.max(1);
+        self
+    }
+
+    /// Set how long the circuit stays open before letting a probe through
+    pub fn with_cooldown(mut self, cooldown: Duration) -> Self {
+        self.cooldown = cooldown;
+        self
+    }
+
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Call `observer` with the old and new state on every transition
+    pub fn on_transition(
+        mut self,
+        observer: impl Fn(CircuitState, CircuitState) + Send + Sync + 'static,
+    ) -> Self {
+        self.observer = Some(Arc::new(observer));
+        self
+    }
+
+    pub fn state(&self) -> Result<CircuitState> {
+        Ok(self.lock()?.circuit)
+    }
+
+    /// Let a call through, or fail fast while the circuit is open
+    fn admit(&self) -> Result<Admission<'_, S>> {
+        let now = self.clock.now();
+        let mut state = self.lock()?;
+        let mut transition = None;
+
+        let probe = match state.circuit {
+            CircuitState::Closed => false,
+            CircuitState::Open if state.opened_at.is_some_and(|at| now >= at + self.cooldown) => {
+                transition = Some((CircuitState::Open, CircuitState::HalfOpen));
+                state.circuit = CircuitState::HalfOpen;
+                true
+            }
+            CircuitState::HalfOpen if !state.probing => true,
+            _ => return Err(AppError::NetworkError("circuit open".into())),
+        };
+        state.probing |= probe;
+        drop(state);
+
+        self.notify(transition);
+        Ok(Admission {
+            breaker: self,
+            probe,
+            settled: false,
+        })
+    }
+
+    fn record(&self, probe: bool, failed: bool) {
+        let now = self.clock.now();
+        let Ok(mut state) = self.state.lock() else {
+            return;
+        };
+        let from = state.circuit;
+        if probe {
+            state.probing = false;
+        }
+
+        if failed {
+            state.failures += 1;
+            let tripped = from == CircuitState::Closed && state.failures >= self.failure_threshold;
+            if probe || tripped {
+                state.circuit = CircuitState::Open;
+                state.opened_at = Some(now);
+            }
+        } else {
+            state.failures = 0;
+            if probe {
+                state.circuit = CircuitState::Closed;
+            }
+        }
+
+        let to = state.circuit;
+        drop(state);
+        self.notify((from != to).then_some((from, to)));
+    }
+
+    fn notify(&self, transition: Option<(CircuitState, CircuitState)>) {
+        if let (Some(observer), Some((from, to))) = (&self.observer, transition) {
+            observer(from, to);
+        }
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, BreakerState>> {
+        self.state
+            .lock()
+            .map_err(|_| AppError::Internal("circuit breaker lock poisoned".into()))
+    }
+}
+
+// A call let through the breaker; one dropped before it settles, such as by a timeout, is a failure
+struct Admission<'a, S> {
+    breaker: &'a CircuitBreaker<S>,
+    probe: bool,
+    settled: bool,
+}
+
+impl<S> Admission<'_, S> {
+    /// Count the call as failed if any of its results is a transient error
+    fn settle<'r>(mut self, results: impl IntoIterator<Item = &'r Result<Option<User>>>) {
+        let failed = results
+            .into_iter()
+            .any(|result| result.as_ref().is_err_and(AppError::is_transient));
+        self.breaker.record(self.probe, failed);
+        self.settled = true;
+    }
+}
+
+impl<S> Drop for Admission<'_, S> {
+    fn drop(&mut self) {
+        if !self.settled {
+            self.breaker.record(self.probe, true);
+        }
+    }
+}
+
+impl<S: UserSource> UserSource for CircuitBreaker<S> {
+    async fn fetch(&self, id: u64) -> Result<Option<User>> {
+        let admission = self.admit()?;
+        let result = self.source.fetch(id).await;
+        admission.settle([&result]);
+        result
+    }
+
+    /// Admit the whole batch as one call, keeping the inner source's own batching
+    async fn fetch_many(&self, ids: &[u64]) -> HashMap<u64, Result<Option<User>>> {
+        if ids.is_empty() {
+            return HashMap::new();
+        }
+        let admission = match self.admit() {
+            Ok(admission) => admission,
+            Err(err) => return ids.iter().map(|&id| (id, Err(err.clone()))).collect(),
+        };
+        let results = self.source.fetch_many(ids).await;
+        admission.settle(results.values());
+        results
+    }
+}
+
+// Async function with lifetimes
+pub async fn fetch_user<S>(cache: &UserCache, source: &S, id: u64) -> Result<Option<User>>
+where
+    S: UserSource,
+{
+    // Try cache first, joining any fetch already running for this id; with no owned
+    // source to refresh from in the background, a stale user is refreshed before returning
+    let flight = match cache.lookup(id)? {
+        Lookup::Hit(user) => return Ok(user),
+        Lookup::Stale(user, None) => return Ok(Some(user)),
+        Lookup::Stale(_, Some(flight)) | Lookup::Miss(flight) | Lookup::Join(flight) => flight,
+    };
+    join_flight(cache, source, id, flight).await
+}
+
+/// Like `fetch_user`, but serve stale users at once and refresh them in the background
+pub async fn fetch_user_shared<S>(
+    cache: &UserCache,
+    source: &Arc<S>,
+    id: u64,
+) -> Result<Option<User>>
+where
+    S: UserSource + Send + 'static,
+{
+    let flight = match cache.lookup(id)? {
+        Lookup::Hit(user) => return Ok(user),
+        Lookup::Stale(user, refresh) => {
+            if let Some(flight) = refresh {
+                spawn_refresh(cache.clone(), Arc::clone(source), id, flight);
+            }
+            return Ok(Some(user));
+        }
+        Lookup::Miss(flight) | Lookup::Join(flight) => flight,
+    };
+    join_flight(cache, &**source, id, flight).await
+}
+
+async fn join_flight<S: UserSource>(
+    cache: &UserCache,
+    source: &S,
+    id: u64,
+    flight: Flight,
+) -> Result<Option<User>> {
+    let guard = FlightGuard { cache, id, flight };
+
+    // Fetch from the backend once, sharing the outcome with every waiter
+    let result = guard
+        .flight
+        .get_or_init(|| load_user(cache, source, id))
+        .await;
+    result.clone()
+}
+
+async fn load_user<S: UserSource>(cache: &UserCache, source: &S, id: u64) -> Result<Option<User>> {
+    let user = tokio::time::timeout(cache.fetch_deadline, source.fetch(id))
+        .await
+        .unwrap_or_else(|_| Err(cache.deadline_error(id)))?;
+    match &user {
+        Some(user) => cache.insert(user.clone())?,
+        None => cache.insert_missing(id)?,
+    }
+    Ok(user)
+}
+
+/// Refresh a stale entry in the background, reporting failures and keeping the stale copy
+fn spawn_refresh<S>(cache: UserCache, source: S, id: u64, flight: Flight)
+where
+    S: UserSource + Send + 'static,
+{
+    tokio::spawn(async move {
+        let guard = FlightGuard {
+            cache: &cache,
+            id,
+            flight,
+        };
+        let result = guard
+            .flight
+            .get_or_init(|| load_user(&cache, &source, id))
+            .await;
+        if let Err(err) = result {
+            (cache.refresh_observer)(id, err);
+        }
+    });
+}
+
+// Outcome of a batch lookup, split by what happened to each id
+#[derive(Debug, Default)]
+pub struct BatchResult {
+    pub users: HashMap<u64, User>,
+    /// Ids the backend has no user for, in ascending order
+    pub missing: Vec<u64>,
+    pub errors: HashMap<u64, AppError>,
+}
+
+/// Look up many users, serving hits from the cache and fetching all misses as one batch
+///
+/// Misses share in-flight fetches with `fetch_user`, so an id is never loaded twice at
+/// once. As in `fetch_user`, a stale user is served while another caller refreshes it
+/// and otherwise refreshed as part of the batch. A failure for one id is reported in
+/// `errors` and does not fail the others.
+pub async fn fetch_users<S: UserSource>(
+    cache: &UserCache,
+    source: &S,
+    ids: &[u64],
+) -> Result<BatchResult> {
+    let mut ids = ids.to_vec();
+    ids.sort_unstable();
+    ids.dedup();
+
+    let mut batch = BatchResult::default();
+    let mut leads = Vec::new();
+    let mut joins = Vec::new();
+    for (id, lookup) in ids.iter().copied().zip(cache.lookup_many(&ids)?) {
+        match lookup {
+            Lookup::Hit(Some(user)) | Lookup::Stale(user, None) => {
+                batch.users.insert(id, user);
+            }
+            Lookup::Hit(None) => batch.missing.push(id),
+            Lookup::Stale(_, Some(flight)) | Lookup::Miss(flight) => {
+                leads.push(FlightGuard { cache, id, flight })
+            }
+            Lookup::Join(flight) => joins.push(FlightGuard { cache, id, flight }),
+        }
+    }
+
+    // The ids this call leads go to the backend as one batch, published through their
+    // flights so that concurrent lookups wait for it rather than fetching again
+    let lead_ids: Vec<u64> = leads.iter().map(|guard| guard.id).collect();
+    let fetched = OnceCell::new();
+    let mut pending: Vec<BoxFuture<'_, (u64, Result<Option<User>>)>> = Vec::new();
+    for guard in &leads {
+        let (fetched, lead_ids) = (&fetched, &lead_ids);
+        pending.push(Box::pin(async move {
+            let load = || async {
+                let results = fetched
+                    .get_or_init(|| load_users(cache, source, lead_ids))
+                    .await;
+                results.get(&guard.id).cloned().unwrap_or_else(|| {
+                    let message = format!("source returned no result for user {}", guard.id);
+                    Err(AppError::Internal(message.into()))
+                })
+            };
+            (guard.id, guard.flight.get_or_init(load).await.clone())
+        }));
+    }
+    for guard in &joins {
+        pending.push(Box::pin(async move {
+            let load = || load_user(cache, source, guard.id);
+            (guard.id, guard.flight.get_or_init(load).await.clone())
+        }));
+    }
+
+    for (id, result) in join_all(pending).await {
+        match result {
+            Ok(Some(user)) => {
+                batch.users.insert(id, user);
+            }
+            Ok(None) => batch.missing.push(id),
+            Err(err) => {
+                batch.errors.insert(id, err);
+            }
+        }
+    }
+    batch.missing.sort_unstable();
+    Ok(batch)
+}
+
+/// Fetch `ids` as one batch within the cache's deadline, caching every answer
+async fn load_users<S: UserSource>(
+    cache: &UserCache,
+    source: &S,
+    ids: &[u64],
+) -> HashMap<u64, Result<Option<User>>> {
+    let load = tokio::time::timeout(cache.fetch_deadline, source.fetch_many(ids));
+    let mut results = load.await.unwrap_or_else(|_| {
+        let timed_out = ids.iter().map(|&id| (id, Err(cache.deadline_error(id))));
+        timed_out.collect()
+    });
+    for (&id, result) in results.iter_mut() {
+        let cached = match result {
+            Ok(Some(user)) => cache.insert(user.clone()),
+            Ok(None) => cache.insert_missing(id),
+            Err(_) => Ok(()),
+        };
+        if let Err(err) = cached {
+            *result = Err(err);
+        }
+    }
+    results
+}
+
+type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Drive `futures` concurrently, collecting their outputs in completion order
+async fn join_all<T>(mut futures: Vec<BoxFuture<'_, T>>) -> Vec<T> {
+    let mut outputs = Vec::with_capacity(futures.len());
+    std::future::poll_fn(|cx| {
+        futures.retain_mut(|future| match future.as_mut().poll(cx) {
+            Poll::Ready(output) => {
+                outputs.push(output);
+                false
+            }
+            Poll::Pending => true,
+        });
+        if futures.is_empty() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    })
+    .await;
+    outputs
+}
+
+// Repository wrapper that keeps a `UserCache` in step with every write
+pub struct CachedRepository<R> {
+    inner: R,
+    cache: UserCache,
+}
+
+impl<R> CachedRepository<R> {
+    pub fn new(inner: R, cache: UserCache) -> Self {
+        Self { inner, cache }
+    }
+
+    pub fn cache(&self) -> &UserCache {
+        &self.cache
+    }
+
+    pub fn inner(&self) -> &R {
+        &self.inner
+    }
+}
+
+// Saves replace the cached copy, including a remembered miss; deletes drop it
+impl<R: Repository<User>> Repository<User> for CachedRepository<R> {
+    fn find_by_id(&self, id: u64) -> Result<Option<User>> {
+        self.inner.find_by_id(id)
+    }
+
+    fn save(&mut self, item: User) -> Result<()> {
+        self.inner.save(item.clone())?;
+        self.cache.insert(item)
+    }
+
+    fn delete(&mut self, id: u64) -> Result<bool> {
+        let removed = self.inner.delete(id)?;
+        self.cache.insert_missing(id)?;
+        Ok(removed)
+    }
+
+    fn version_of(&self, id: u64) -> Result<u64> {
+        self.inner.version_of(id)
+    }
+
+    fn save_if_version(&mut self, item: User, expected: u64) -> Result<u64> {
+        let version = self.inner.save_if_version(item.clone(), expected)?;
+        self.cache.insert(item)?;
+        Ok(version)
+    }
+
+    fn find_all(&self) -> Result<Vec<User>> {
+        self.inner.find_all()
+    }
+
+    fn find_where<F: Fn(&User) -> bool>(&self, predicate: F) -> Result<Vec<User>> {
+        self.inner.find_where(predicate)
+    }
+
+    fn count_where<F: Fn(&User) -> bool>(&self, predicate: F) -> Result<usize> {
+        self.inner.count_where(predicate)
+    }
+
+    fn list(&self, page: usize, page_size: usize) -> Result<Page<User>> {
+        self.inner.list(page, page_size)
+    }
+}
+
+// Async writes for a shared repository, with the same cache upkeep
+impl CachedRepository<SharedRepository<User>> {
+    pub async fn save(&self, item: User) -> Result<()> {
+        self.inner.save(item.clone()).await?;
+        self.cache.insert(item)
+    }
+
+    pub async fn delete(&self, id: u64) -> Result<bool> {
+        let removed = self.inner.delete(id).await?;
+        self.cache.insert_missing(id)?;
+        Ok(removed)
+    }
+
+    pub async fn save_if_version(&self, item: User, expected: u64) -> Result<u64> {
+        let version = self.inner.save_if_version(item.clone(), expected).await?;
+        self.cache.insert(item)?;
+        Ok(version)
+    }
+}
+
+impl<R: UserSource> UserSource for CachedRepository<R> {
+    fn fetch(&self, id: u64) -> impl Future<Output = Result<Option<User>>> + Send {
+        self.inner.fetch(id)
+    }
+}
+
+// Macro usage
+macro_rules! create_users {
+    ($($id:expr => $name:expr),+ $(,)?) => {
+        vec![
+            $(
+                User::try_new($id, $name, format!("{}@example.com", $name.to_lowercase()))?
+            ),+
+        ]
+    };
+}
+
+// Main function
+#[tokio::main]
+async fn main() -> Result<()> {
+    // Create users using macro
+    let users = create_users![
+        1 => "Alice",
+        2 => "Bob",
+        3 => "Charlie",
+    ];
+
+    // Initialize repository
+    let mut repo: InMemoryRepository<User> = InMemoryRepository::new();
+
+    // Save users
+    for user in users {
+        println!("Saving: {}", user);
+        repo.save(user).context("seeding sample users")?;
+    }
+
+    // Query with pattern matching
+    if let Some(user) = repo.find_by_id(1)? {
+        println!("Found: {}", user);
+        
+        match user.role {
+            UserRole::Admin => println!("  -> Has admin privileges"),
+            UserRole::User => println!("  -> Regular user"),
+            UserRole::Guest => println!("  -> Limited access"),
+        }
+    }
+
+    // Predicate queries
+    let admin_count = repo.count_where(|u| u.is_admin())?;
+
+    println!("Admin count: {}", admin_count);
+
+    // Closure
+    let format_user = |u: &User| -> String {
+        format!("[{}] {} ({})", u.id, u.name, u.role as u8)
+    };
+
+    for user in &repo.find_all()? {
+        println!("{}", format_user(user));
+    }
+
+    // String formatting
+    let message = format!(
+        "Repository contains {} user{}",
+        repo.len(),
+        if repo.len() == 1 { "" } else { "s" }
+    );
+    println!("{}", message);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    // In-process HTTP server answering each connection with the next canned response
+    //
+    // A status of 0 reads the request and never answers, to simulate a hung backend.
+    struct MockServer {
+        base_url: String,
+        hits: Arc<AtomicUsize>,
+        paths: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl MockServer {
+        fn start(responses: Vec<(u16, &'static str)>) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let base_url = format!("http://{}", listener.local_addr().unwrap());
+            let hits = Arc::new(AtomicUsize::new(0));
+            let paths = Arc::new(Mutex::new(Vec::new()));
+
+            let (hit_counter, seen_paths) = (Arc::clone(&hits), Arc::clone(&paths));
+            std::thread::spawn(move || {
+                let mut hung = Vec::new();
+                for (status, body) in responses {
+                    let Ok((mut stream, _)) = listener.accept() else {
+                        return;
+                    };
+                    let mut request = [0u8; 4096];
+                    let read = stream.read(&mut request).unwrap_or(0);
+                    let request = String::from_utf8_lossy(&request[..read]);
+                    if let Some(path) = request.split_whitespace().nth(1) {
+                        seen_paths.lock().unwrap().push(path.to_string());
+                    }
+                    hit_counter.fetch_add(1, Ordering::SeqCst);
+                    if status == 0 {
+                        hung.push(stream);
+                        continue;
+                    }
+                    let _ = write!(
+                        stream,
+                        "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        status,
+                        body.len(),
+                        body
+                    );
+                }
+                // Keep hung connections open for the rest of the test run
+                if !hung.is_empty() {
+                    loop {
+                        std::thread::park();
+                    }
+                }
+            });
+
+            Self {
+                base_url,
+                hits,
+                paths,
+            }
+        }
+
+        fn client(&self) -> HttpUserClient {
+            HttpUserClient::new(&self.base_url).with_backoff(Duration::from_millis(1))
+        }
+
+        fn hits(&self) -> usize {
+            self.hits.load(Ordering::SeqCst)
+        }
+    }
+
+    // Fresh path under the system temp dir, removed when dropped
+    struct TempPath(PathBuf);
+
+    impl TempPath {
+        fn new(name: &str) -> Self {
+            let file = format!("mishu-sample-{}-{}", std::process::id(), name);
+            let path = std::env::temp_dir().join(file);
+            let _ = fs::remove_file(&path);
+            Self(path)
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    const ALICE_JSON: &str =
+        r#"{"id":7,"name":"Alice","email":"alice@example.com","role":"admin"}"#;
+
+    #[test]
+    fn test_user_creation() {
+        let user = User::new(1, "Test", "test@example.com");
+        assert_eq!(user.id, 1);
+        assert_eq!(user.name, "Test");
+        assert_eq!(user.role, UserRole::User);
+    }
+
+    #[test]
+    fn test_admin_user() {
+        let admin = User::admin(1, "Admin", "admin@example.com");
+        assert!(admin.is_admin());
+    }
+
+    #[test]
+    fn test_repository() -> Result<()> {
+        let mut repo = InMemoryRepository::new();
+        let user = User::new(1, "Test", "test@example.com");
+        
+        repo.save(user.clone())?;
+        
+        let found = repo.find_by_id(1)?;
+        assert!(found.is_some());
+        assert_eq!(found.unwrap().name, "Test");
+        
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fetch_user_decodes_response() -> Result<()> {
+        let server = MockServer::start(vec![(200, ALICE_JSON)]);
+        let cache = UserCache::default();
+
+        let user = fetch_user(&cache, &server.client(), 7).await?.unwrap();
+        assert_eq!(user.name, "Alice");
+        assert!(user.is_admin());
+        assert_eq!(*server.paths.lock().unwrap(), vec!["/users/7".to_string()]);
+
+        // Second lookup is served from the cache
+        fetch_user(&cache, &server.client(), 7).await?;
+        assert_eq!(server.hits(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fetch_user_maps_status_codes() {
+        let server = MockServer::start(vec![(404, ""), (401, "")]);
+        let client = server.client();
+
+        let missing = client.get_user(1).await;
+        assert!(matches!(missing, Err(AppError::NotFound(_))));
+
+        let denied = client.get_user(1).await;
+        assert!(matches!(denied, Err(AppError::Unauthorized(_))));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_user_does_not_retry_client_errors() {
+        let server = MockServer::start(vec![(422, ""), (429, ""), (200, ALICE_JSON)]);
+        let client = server.client();
+
+        let rejected = client.get_user(7).await;
+        assert!(matches!(rejected, Err(AppError::Rejected(_))));
+        assert_eq!(server.hits(), 1);
+
+        // Rate limiting is transient, so the next lookup retries past it
+        assert!(client.get_user(7).await.is_ok());
+        assert_eq!(server.hits(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_get_user_survives_many_retries() {
+        let server = MockServer::start(vec![(503, ""); 41]);
+        let client = server
+            .client()
+            .with_backoff(Duration::ZERO)
+            .with_max_retries(40);
+
+        let result = client.get_user(1).await;
+        assert!(matches!(result, Err(AppError::NetworkError(_))));
+        assert_eq!(server.hits(), 41);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_user_missing_is_none() -> Result<()> {
+        let server = MockServer::start(vec![(404, "")]);
+        let cache = UserCache::default();
+
+        assert!(fetch_user(&cache, &server.client(), 1).await?.is_none());
+        assert!(cache.is_empty()?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fetch_user_retries_server_errors() -> Result<()> {
+        let server = MockServer::start(vec![(503, ""), (500, ""), (200, ALICE_JSON)]);
+        let cache = UserCache::default();
+
+        let user = fetch_user(&cache, &server.client(), 7).await?;
+        assert!(user.is_some());
+        assert_eq!(server.hits(), 3);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fetch_user_gives_up_after_max_retries() {
+        let responses = vec![(500, ""); MAX_RETRIES as usize + 1];
+        let server = MockServer::start(responses);
+
+        let result = server.client().get_user(7).await;
+        assert!(matches!(result, Err(AppError::NetworkError(_))));
+        assert_eq!(server.hits(), MAX_RETRIES as usize + 1);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_user_reads_through_cache() -> Result<()> {
+        let source = MockUserSource::new().with_user(User::new(1, "Test", "test@example.com"));
+        let cache = UserCache::default();
+
+        assert_eq!(fetch_user(&cache, &source, 1).await?.unwrap().name, "Test");
+        assert_eq!(fetch_user(&cache, &source, 1).await?.unwrap().name, "Test");
+        assert!(fetch_user(&cache, &source, 2).await?.is_none());
+        assert_eq!(source.calls(), 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fetch_user_from_repository() -> Result<()> {
+        let mut repo = InMemoryRepository::new();
+        repo.save(User::new(1, "Test", "test@example.com"))?;
+        let cache = UserCache::default();
+
+        assert!(fetch_user(&cache, &repo, 1).await?.is_some());
+        assert!(cache.get(1)?.is_some());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fetch_user_propagates_source_errors() {
+        let source = MockUserSource::new().failing("backend down");
+        let cache = UserCache::default();
+
+        let result = fetch_user(&cache, &source, 1).await;
+        assert!(matches!(result, Err(AppError::NetworkError(_))));
+        assert!(cache.is_empty().unwrap());
+    }
+
+    #[test]
+    fn test_insert_new_allocates_ids() -> Result<()> {
+        let mut repo = InMemoryRepository::new();
+
+        let first = repo.insert_new(User::new(0, "A", "a@example.com"))?;
+        let second = repo.insert_new(User::new(0, "B", "b@example.com"))?;
+        assert_eq!((first, second), (1, 2));
+        assert_eq!(repo.find_by_id(2)?.unwrap().id, 2);
+
+        // Deleted ids are never handed out again
+        repo.delete(second)?;
+        assert_eq!(repo.insert_new(User::new(0, "C", "c@example.com"))?, 3);
+
+        // Explicit saves push the counter past their id
+        repo.save(User::new(10, "D", "d@example.com"))?;
+        assert_eq!(repo.insert_new(User::new(0, "E", "e@example.com"))?, 11);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reseed_from_existing_data() -> Result<()> {
+        let mut repo = InMemoryRepository::from_items(vec![
+            User::new(4, "A", "a@example.com"),
+            User::new(9, "B", "b@example.com"),
+        ])?;
+        assert_eq!(repo.insert_new(User::new(0, "C", "c@example.com"))?, 10);
+
+        repo.reseed(100);
+        assert_eq!(repo.insert_new(User::new(0, "D", "d@example.com"))?, 101);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_insert_new_reports_exhausted_ids() -> Result<()> {
+        let mut repo = InMemoryRepository::new();
+        repo.save(User::new(u64::MAX, "Last", "last@example.com"))?;
+
+        let result = repo.insert_new(User::new(0, "Next", "next@example.com"));
+        assert!(matches!(result, Err(AppError::Storage(_))));
+        assert_eq!(repo.count_where(|_| true)?, 1);
+
+        Ok(())
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Team {
+        id: u64,
+        name: String,
+    }
+
+    impl Entity for Team {
+        fn id(&self) -> u64 {
+            self.id
+        }
+    }
+
+    #[test]
+    fn test_repository_for_other_entities() -> Result<()> {
+        let mut repo = InMemoryRepository::new();
+        let team = Team {
+            id: 5,
+            name: "Platform".to_string(),
+        };
+
+        repo.save(team.clone())?;
+        assert_eq!(repo.find_by_id(5)?, Some(team));
+        assert!(repo.delete(5)?);
+        assert!(repo.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_repository_queries() -> Result<()> {
+        let mut repo = InMemoryRepository::new();
+        repo.save(User::new(3, "Carol", "carol@example.com"))?;
+        repo.save(User::admin(1, "Alice", "alice@example.com"))?;
+        repo.save(User::new(2, "Bob", "bob@example.com"))?;
+
+        let ids: Vec<u64> = repo.find_all()?.iter().map(|u| u.id).collect();
+        assert_eq!(ids, vec![1, 2, 3]);
+
+        let regular = repo.find_where(|u| !u.is_admin())?;
+        assert_eq!(regular.len(), 2);
+        assert_eq!(regular[0].name, "Bob");
+        assert_eq!(repo.count_where(|u| u.is_admin())?, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_repository_pagination() -> Result<()> {
+        let mut repo = InMemoryRepository::new();
+        for id in (1..=5).rev() {
+            let email = format!("user{}@example.com", id);
+            repo.save(User::new(id, format!("User {}", id), email))?;
+        }
+
+        let first = repo.list(0, 2)?;
+        let ids: Vec<u64> = first.items.iter().map(|u| u.id).collect();
+        assert_eq!(ids, vec![1, 2]);
+        assert_eq!((first.total, first.next_cursor), (5, Some(1)));
+
+        let last = repo.list(2, 2)?;
+        assert_eq!(last.items.len(), 1);
+        assert_eq!(last.next_cursor, None);
+
+        assert!(repo.list(9, 2)?.items.is_empty());
+        let far = repo.list(usize::MAX, 10)?;
+        assert!(far.items.is_empty());
+        assert_eq!(far.next_cursor, None);
+        assert!(matches!(repo.list(0, 0), Err(AppError::ParseError { .. })));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_email_index() -> Result<()> {
+        let mut repo = InMemoryRepository::new();
+        repo.save(User::new(1, "Alice", "Alice@Example.com"))?;
+
+        assert_eq!(repo.find_by_email("alice@example.COM")?.unwrap().id, 1);
+        assert!(repo.find_by_email("bob@example.com")?.is_none());
+
+        let duplicate = repo.save(User::new(2, "Imposter", "ALICE@example.com"));
+        assert!(matches!(duplicate, Err(AppError::Conflict(_))));
+        assert!(repo.find_by_id(2)?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_email_index_follows_updates_and_deletes() -> Result<()> {
+        let mut repo = InMemoryRepository::new();
+        repo.save(User::new(1, "Alice", "alice@example.com"))?;
+
+        // Re-saving with the same email is not a conflict
+        repo.save(User::new(1, "Alice Smith", "alice@example.com"))?;
+
+        repo.save(User::new(1, "Alice", "alice@work.example.com"))?;
+        assert!(repo.find_by_email("alice@example.com")?.is_none());
+        repo.save(User::new(2, "Alicia", "alice@example.com"))?;
+
+        repo.delete(1)?;
+        assert!(repo.find_by_email("alice@work.example.com")?.is_none());
+        repo.save(User::new(3, "Al", "alice@work.example.com"))?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_repository_persists() -> Result<()> {
+        let path = TempPath::new("persists.jsonl");
+        {
+            let mut repo = FileRepository::open(&path.0)?;
+            repo.save(User::admin(1, "Alice", "alice@example.com").with_metadata("team", "core"))?;
+            repo.save(User::new(2, "Bob", "bob@example.com"))?;
+            repo.save(User::new(2, "Robert", "bob@example.com"))?;
+            assert!(repo.delete(1)?);
+        }
+
+        let repo = FileRepository::open(&path.0)?;
+        assert!(repo.find_by_id(1)?.is_none());
+        assert_eq!(repo.find_by_id(2)?.unwrap().name, "Robert");
+        assert_eq!(repo.find_by_email("BOB@example.com")?.unwrap().id, 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_repository_compaction() -> Result<()> {
+        let path = TempPath::new("compaction.jsonl");
+        let mut repo = FileRepository::open(&path.0)?;
+        for name in ["Ann", "Anna", "Annie"] {
+            repo.save(User::new(1, name, "ann@example.com"))?;
+        }
+        repo.save(User::new(2, "Bob", "bob@example.com"))?;
+
+        repo.compact()?;
+        assert_eq!(fs::read_to_string(&path.0)?.lines().count(), 2);
+
+        // Appends after compaction land in the snapshot file
+        repo.save(User::new(3, "Cy", "cy@example.com"))?;
+        let reopened = FileRepository::open(&path.0)?;
+        assert_eq!(reopened.find_by_id(1)?.unwrap().name, "Annie");
+        assert_eq!(reopened.find_all()?.len(), 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_repository_reports_corrupt_lines() -> Result<()> {
+        let path = TempPath::new("corrupt.jsonl");
+        let valid = json!({ "op": "save", "user": User::new(1, "A", "a@example.com").to_json() });
+        fs::write(&path.0, format!("{}\n{{not json\n", valid))?;
+
+        let err = FileRepository::open(&path.0).err().unwrap();
+        assert!(matches!(err, AppError::ParseError { ref field, .. } if field == "line 2"));
+        assert!(err.source().is_some_and(|cause| cause.is::<AppError>()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sqlite_repository_round_trip() -> Result<()> {
+        let mut repo = SqliteRepository::open_in_memory()?;
+        assert_eq!(repo.schema_version()?, SQLITE_MIGRATIONS.len());
+
+        let alice = User::admin(1, "Alice", "alice@example.com").with_metadata("team", "core");
+        repo.save(alice)?;
+        repo.save(User::new(2, "Bob", "bob@example.com"))?;
+
+        let found = repo.find_by_id(1)?.unwrap();
+        assert_eq!(found.role, UserRole::Admin);
+        assert_eq!(found.metadata.unwrap()["team"], "core");
+        assert_eq!(repo.find_by_email("BOB@example.com")?.unwrap().id, 2);
+
+        // Updates replace the metadata set
+        repo.save(User::new(1, "Alice", "alice@example.com"))?;
+        assert!(repo.find_by_id(1)?.unwrap().metadata.is_none());
+
+        assert!(repo.delete(2)?);
+        assert!(!repo.delete(2)?);
+        assert_eq!(repo.list(0, 10)?.total, 1);
+        assert!(repo.list(usize::MAX, 10)?.items.is_empty());
+
+        // SQLite cannot hold ids past i64::MAX, which is a bad id rather than a storage fault
+        let huge = repo.save(User::new(u64::MAX, "Huge", "huge@example.com"));
+        assert!(matches!(huge, Err(AppError::ParseError { ref field, .. }) if field == "id"));
+        let lookup = repo.find_by_id(u64::MAX);
+        assert!(matches!(lookup, Err(AppError::ParseError { .. })));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sqlite_repository_rejects_duplicate_email() -> Result<()> {
+        let mut repo = SqliteRepository::open_in_memory()?;
+        repo.save(User::new(1, "Alice", "alice@example.com"))?;
+
+        let duplicate = repo.save(User::new(2, "Imposter", "Alice@Example.com"));
+        assert!(matches!(duplicate, Err(AppError::Conflict(_))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sqlite_repository_reopens_migrated_file() -> Result<()> {
+        let path = TempPath::new("users.sqlite3");
+        SqliteRepository::open(&path.0)?.save(User::new(1, "Alice", "alice@example.com"))?;
+
+        let repo = SqliteRepository::open(&path.0)?;
+        assert_eq!(repo.schema_version()?, SQLITE_MIGRATIONS.len());
+        assert_eq!(repo.find_by_id(1)?.unwrap().name, "Alice");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_app_error_context_chain() {
+        let lookup: Result<()> = Err(AppError::Timeout("user 7".into()));
+        let err = lookup
+            .context("loading profile")
+            .with_context(|| format!("page {}", 3))
+            .unwrap_err();
+
+        let message = err.to_string();
+        assert_eq!(message, "page 3: loading profile: Timed out: user 7");
+        assert_eq!(err.detail().context(), ["page 3", "loading profile"]);
+        assert!(matches!(err, AppError::Timeout(ref msg) if msg == "user 7"));
+        assert_eq!(err.kind(), "timeout");
+        assert_eq!(err.code(), "E004");
+        assert!(err.is_transient());
+    }
+
+    #[test]
+    fn test_app_error_preserves_source() {
+        let io = io::Error::new(io::ErrorKind::PermissionDenied, "read-only volume");
+        let err = AppError::from(io).context("compacting log");
+
+        assert!(matches!(err, AppError::Storage(_)));
+        let source = err.source().expect("io error kept as source");
+        assert_eq!(source.to_string(), "read-only volume");
+        assert!(source.downcast_ref::<io::Error>().is_some());
+        assert!(AppError::Unauthorized("guest".into()).source().is_none());
+    }
+
+    #[test]
+    fn test_app_error_codes_are_distinct() {
+        let errors = [
+            AppError::NotFound("".into()),
+            AppError::Unauthorized("".into()),
+            AppError::NetworkError("".into()),
+            AppError::Timeout("".into()),
+            AppError::parse("field", "message"),
+            AppError::Conflict("".into()),
+            AppError::version_conflict(1, 2),
+            AppError::Storage("".into()),
+            AppError::Internal("".into()),
+            AppError::Rejected("".into()),
+        ];
+        let codes: std::collections::HashSet<_> = errors.iter().map(AppError::code).collect();
+        assert_eq!(codes.len(), errors.len());
+    }
+
+    #[test]
+    fn test_user_json_format() -> Result<()> {
+        let user = User::admin(1, "Alice", "alice@example.com");
+        let expected = json!({
+            "id": 1,
+            "name": "Alice",
+            "email": "alice@example.com",
+            "role": "admin",
+        });
+        assert_eq!(user.to_json(), expected);
+
+        let granted = User::new(3, "Carol", "carol@example.com").grant(Permission::ManageRoles);
+        let tagged = granted.with_metadata("team", "core");
+        let decoded = User::from_json(&tagged.to_json())?;
+        assert_eq!(decoded.metadata, tagged.metadata);
+        assert_eq!(decoded.permissions(), tagged.permissions());
+
+        // Records written before roles existed still load
+        let legacy = json!({ "id": 2, "name": "Bob", "email": "bob@example.com" });
+        assert_eq!(User::from_json(&legacy)?.role, UserRole::User);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_user_serde_format() -> std::result::Result<(), serde_json::Error> {
+        let user = User::admin(1, "Alice", "alice@example.com");
+        let value = serde_json::to_value(&user)?;
+        assert_eq!(value.get("role"), Some(&json!("admin")));
+        assert!(value.get("metadata").is_none());
+
+        let tagged = user.with_metadata("team", "core");
+        let decoded: User = serde_json::from_str(&serde_json::to_string(&tagged)?)?;
+        assert_eq!(decoded.role, UserRole::Admin);
+        assert_eq!(decoded.metadata, tagged.metadata);
+
+        for role in [UserRole::Admin, UserRole::User, UserRole::Guest] {
+            assert_eq!(serde_json::to_value(role)?, json!(role.as_str()));
+        }
+
+        // The JSON helpers are the serde format
+        assert_eq!(tagged.to_json(), serde_json::to_value(&tagged)?);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_app_error_serde_round_trip() -> std::result::Result<(), serde_json::Error> {
+        let errors = vec![
+            AppError::NotFound("user 1".into()),
+            AppError::Unauthorized("guest".into()),
+            AppError::NetworkError("connection reset".into()),
+            AppError::Timeout("user 1 took longer than 5s".into()),
+            AppError::parse("email", "missing @"),
+            AppError::Conflict("email taken".into()),
+            AppError::version_conflict(1, 2),
+            AppError::Storage("disk full".into()),
+            AppError::Internal("lock poisoned".into()),
+            AppError::Rejected("HTTP 422".into()),
+            AppError::NotFound("user 1".into())
+                .context("loading profile")
+                .context("rendering"),
+        ];
+
+        for err in errors {
+            let value = serde_json::to_value(&err)?;
+            assert_eq!(value.get("kind"), Some(&json!(err.kind())));
+
+            let decoded: AppError = serde_json::from_value(value)?;
+            assert_eq!(decoded.kind(), err.kind());
+            assert_eq!(decoded.code(), err.code());
+            assert_eq!(decoded.to_string(), err.to_string());
+        }
+
+        let parse = serde_json::to_value(AppError::parse("email", "missing @"))?;
+        assert_eq!(parse.get("field"), Some(&json!("email")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_role_conversions() -> Result<()> {
+        for role in [UserRole::Admin, UserRole::User, UserRole::Guest] {
+            assert_eq!(UserRole::try_from(role as u8)?, role);
+            assert_eq!(role.to_string().parse::<UserRole>()?, role);
+        }
+
+        assert_eq!(UserRole::Guest as u8, 2);
+        assert_eq!(" ADMIN ".parse::<UserRole>()?, UserRole::Admin);
+        assert_eq!("Guest".parse::<UserRole>()?, UserRole::Guest);
+
+        let unknown = "root".parse::<UserRole>();
+        assert!(matches!(unknown, Err(AppError::ParseError { ref field, .. }) if field == "role"));
+        assert!(UserRole::try_from(3).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_user_permissions() {
+        let guest = User {
+            role: UserRole::Guest,
+            ..User::new(1, "Guest", "guest@example.com")
+        };
+        assert!(guest.can(Permission::ReadUser));
+        assert!(!guest.can(Permission::WriteUser));
+
+        let admin = User::admin(2, "Admin", "admin@example.com");
+        assert!(Permission::ALL.iter().all(|p| admin.can(*p)));
+
+        let moderator = User::new(3, "Mod", "mod@example.com")
+            .grant(Permission::DeleteUser)
+            .deny(Permission::WriteUser);
+        assert!(moderator.can(Permission::DeleteUser));
+        assert!(!moderator.can(Permission::WriteUser));
+
+        let restricted = User::admin(4, "Ops", "ops@example.com").deny(Permission::ManageRoles);
+        assert!(!restricted.can(Permission::ManageRoles));
+        assert_eq!(Permissions::from_bits(u32::MAX), Permissions::all());
+    }
+
+    #[test]
+    fn test_authorized_repository() -> Result<()> {
+        let admin = User::admin(1, "Admin", "admin@example.com");
+        let user = User::new(2, "User", "user@example.com");
+        let guest = User {
+            role: UserRole::Guest,
+            ..User::new(3, "Guest", "guest@example.com")
+        };
+
+        let mut repo = AuthorizedRepository::new(InMemoryRepository::new());
+        repo.save(&admin, admin.clone())?;
+        repo.save(&admin, user.clone())?;
+
+        assert!(repo.find_by_id(&guest, 2)?.is_some());
+        let denied = repo.save(&guest, guest.clone());
+        assert!(matches!(denied, Err(AppError::Unauthorized(_))));
+
+        // Role changes need ManageRoles
+        let promoted = User::admin(2, "User", "user@example.com");
+        let escalation = repo.save(&user, promoted.clone());
+        assert!(matches!(escalation, Err(AppError::Unauthorized(_))));
+        let self_grant = repo.save(&user, user.clone().grant(Permission::DeleteUser));
+        assert!(matches!(self_grant, Err(AppError::Unauthorized(_))));
+        repo.save(&admin, promoted)?;
+
+        let denied = repo.delete(&user, 1);
+        assert!(matches!(denied, Err(AppError::Unauthorized(_))));
+        assert!(repo.delete(&admin, 2)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_authorized_repository_ownership() -> Result<()> {
+        let admin = User::admin(1, "Admin", "admin@example.com");
+        let alice = User::new(2, "Alice", "alice@example.com");
+        let bob = User::new(3, "Bob", "bob@example.com");
+        let guest = User {
+            role: UserRole::Guest,
+            ..User::new(4, "Guest", "guest@example.com")
+        };
+
+        let mut repo = AuthorizedRepository::new(InMemoryRepository::new());
+        for user in [&admin, &alice, &bob, &guest] {
+            repo.save(&admin, user.clone())?;
+        }
+
+        // Users edit their own record only
+        repo.save(&alice, User::new(2, "Alice Smith", "alice@example.com"))?;
+        let other = repo.save(&alice, User::new(3, "Hacked", "bob@example.com"));
+        assert!(matches!(other, Err(AppError::Unauthorized(_))));
+
+        // Guests cannot write, not even themselves
+        let renamed = User::new(4, "G", "guest@example.com");
+        let own = repo.save(&guest, renamed);
+        assert!(matches!(own, Err(AppError::Unauthorized(_))));
+
+        // Deleting someone else needs more than DeleteUser
+        let deleter = bob.clone().grant(Permission::DeleteUser);
+        let foreign = repo.delete(&deleter, 2);
+        assert!(matches!(foreign, Err(AppError::Unauthorized(_))));
+        assert!(repo.delete(&deleter, 3)?);
+
+        repo.save(&admin, User::new(2, "Alice", "alice@example.com"))?;
+        assert_eq!(repo.find_by_id(&guest, 2)?.unwrap().name, "Alice");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_try_new_validates() -> Result<()> {
+        let user = User::try_new(1, "  Alice  ", " alice.smith+tag@mail.example.com ")?;
+        assert_eq!(user.name, "Alice");
+        assert_eq!(user.email, "alice.smith+tag@mail.example.com");
+
+        let field_of = |result: Result<User>| match result {
+            Err(AppError::ParseError { field, .. }) => field,
+            other => panic!("expected a parse error, got {:?}", other),
+        };
+        let long_name = "x".repeat(MAX_NAME_LEN + 1);
+        for name in ["   ", long_name.as_str(), "Al\u{7}ice"] {
+            assert_eq!(field_of(User::try_new(1, name, "a@example.com")), "name");
+        }
+
+        let bad_emails = [
+            "alice",
+            "@example.com",
+            "alice@",
+            "alice@localhost",
+            "a..b@example.com",
+            "al ice@example.com",
+            "alice@-example.com",
+        ];
+        for email in bad_emails {
+            assert_eq!(field_of(User::try_new(1, "Alice", email)), "email");
+        }
+
+        Ok(())
+    }
 
-    // Initialize repository
-    let mut repo: InMemoryRepository<User> = InMemoryRepository::new();
+    #[test]
+    fn test_user_builder() -> Result<()> {
+        let guest = User::builder()
+            .id(5)
+            .name(" Gina ")
+            .email("gina@example.com")
+            .role(UserRole::Guest)
+            .metadata("team", "core")
+            .metadata_map(HashMap::from([("locale".to_string(), "de".to_string())]))
+            .build()?;
 
-    // Save users
-    for user in users {
-        println!("Saving: {}", user);
-        repo.save(user)?;
+        assert_eq!((guest.id, guest.name.as_str()), (5, "Gina"));
+        assert_eq!(guest.role, UserRole::Guest);
+        assert_eq!(guest.metadata.as_ref().map(HashMap::len), Some(2));
+
+        let plain = User::builder()
+            .name("Pat")
+            .email("pat@example.com")
+            .build()?;
+        assert_eq!(plain.role, UserRole::User);
+        assert!(plain.metadata.is_none());
+
+        match User::builder().name("Pat").build() {
+            Err(AppError::ParseError { field, .. }) => assert_eq!(field, "email"),
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+
+        Ok(())
     }
 
-    // Query with pattern matching
-    if let Some(user) = repo.find_by_id(1)? {
-        println!("Found: {}", user);
-        
-        match user.role {
-            UserRole::Admin => println!("  -> Has admin privileges"),
-            UserRole::User => println!("  -> Regular user"),
-            UserRole::Guest => println!("  -> Limited access"),
+    #[test]
+    fn test_metadata_accessors() -> Result<()> {
+        let mut user = User::new(1, "Test", "test@example.com")
+            .with_metadata("age", "42")
+            .with_metadata("locale", "en");
+
+        assert_eq!(user.get_metadata("locale"), Some("en"));
+        assert_eq!(user.get_metadata_as::<u32>("age")?, Some(42));
+        assert_eq!(user.get_metadata_as::<u32>("missing")?, None);
+        match user.get_metadata_as::<u32>("locale") {
+            Err(AppError::ParseError { field, .. }) => assert_eq!(field, "locale"),
+            other => panic!("expected a parse error, got {:?}", other),
         }
+
+        let mut keys: Vec<&str> = user.metadata_iter().map(|(key, _)| key).collect();
+        keys.sort_unstable();
+        assert_eq!(keys, vec!["age", "locale"]);
+
+        assert_eq!(user.remove_metadata("age"), Some("42".to_string()));
+        assert_eq!(user.remove_metadata("locale"), Some("en".to_string()));
+        assert!(user.metadata.is_none());
+
+        Ok(())
     }
 
-    // Iterator methods
-    let admin_count = users
-        .iter()
-        .filter(|u| u.is_admin())
-        .count();
+    #[test]
+    fn test_metadata_limits() -> Result<()> {
+        let mut user = User::new(1, "Test", "test@example.com");
+        for i in 0..MAX_METADATA_KEYS {
+            user.set_metadata(format!("key{}", i), "v")?;
+        }
+        // Replacing an existing key does not count as a new one
+        user.set_metadata("key0", "w")?;
+        assert!(user.set_metadata("one-too-many", "v").is_err());
 
-    println!("Admin count: {}", admin_count);
+        let mut user = User::new(2, "Test", "test2@example.com");
+        let blob = "x".repeat(MAX_METADATA_BYTES);
+        assert!(user.set_metadata("blob", &blob).is_err());
 
-    // Closure
-    let format_user = |u: &User| -> String {
-        format!("[{}] {} ({})", u.id, u.name, u.role as u8)
-    };
+        let oversized = User::builder()
+            .name("Test")
+            .email("test@example.com")
+            .metadata("blob", "x".repeat(MAX_METADATA_BYTES))
+            .build();
+        assert!(matches!(oversized, Err(AppError::ParseError { .. })));
 
-    for user in &users {
-        println!("{}", format_user(user));
+        // Persistent repositories refuse records that skipped the checks
+        let path = TempPath::new("limits.jsonl");
+        let mut repo = FileRepository::open(&path.0)?;
+        let unchecked = User::new(3, "Test", "t@example.com").with_metadata("blob", blob);
+        assert!(repo.save(unchecked).is_err());
+        assert!(FileRepository::open(&path.0)?.find_by_id(3)?.is_none());
+
+        Ok(())
     }
 
-    // String formatting
-    let message = format!(
-        "Repository contains {} user{}",
-        repo.len(),
-        if repo.len() == 1 { "" } else { "s" }
-    );
-    println!("{}", message);
+    #[test]
+    fn test_sqlite_repository_limits_metadata() -> Result<()> {
+        let mut repo = SqliteRepository::open_in_memory()?;
+        let blob = "x".repeat(MAX_METADATA_BYTES);
+        let unchecked = User::new(1, "Test", "t@example.com").with_metadata("blob", blob);
 
-    Ok(())
-}
+        let rejected = repo.save(unchecked);
+        assert!(matches!(rejected, Err(AppError::ParseError { .. })));
+        assert!(repo.find_by_id(1)?.is_none());
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        Ok(())
+    }
 
     #[test]
-    fn test_user_creation() {
-        let user = User::new(1, "Test", "test@example.com");
-        assert_eq!(user.id, 1);
-        assert_eq!(user.name, "Test");
-        assert_eq!(user.role, UserRole::User);
+    fn test_save_if_version() -> Result<()> {
+        let mut repo = InMemoryRepository::new();
+        let user = |name| User::new(1, name, "a@example.com");
+        assert_eq!(repo.version_of(1)?, 0);
+        assert_eq!(repo.save_if_version(user("A"), 0)?, 1);
+
+        // Two editors start from version 1; the second one loses
+        assert_eq!(repo.save_if_version(user("B"), 1)?, 2);
+        match repo.save_if_version(user("C"), 1) {
+            Err(AppError::VersionConflict {
+                expected, actual, ..
+            }) => {
+                assert_eq!((expected, actual), (1, 2));
+            }
+            other => panic!("expected a version conflict, got {:?}", other),
+        }
+        assert_eq!(repo.find_by_id(1)?.unwrap().name, "B");
+
+        // Plain saves still bump the version
+        repo.save(user("D"))?;
+        assert_eq!(repo.version_of(1)?, 3);
+
+        Ok(())
     }
 
     #[test]
-    fn test_admin_user() {
-        let admin = User::admin(1, "Admin", "admin@example.com");
-        assert!(admin.is_admin());
+    fn test_file_repository_persists_versions() -> Result<()> {
+        let path = TempPath::new("versions.jsonl");
+        let user = |name| User::new(1, name, "a@example.com");
+        {
+            let mut repo = FileRepository::open(&path.0)?;
+            repo.save(user("A"))?;
+            repo.save_if_version(user("B"), 1)?;
+            repo.compact()?;
+        }
+
+        let mut repo = FileRepository::open(&path.0)?;
+        assert_eq!(repo.version_of(1)?, 2);
+        let stale = repo.save_if_version(user("C"), 1);
+        assert!(matches!(stale, Err(AppError::VersionConflict { .. })));
+
+        // Tombstones survive both replay and compaction
+        repo.delete(1)?;
+        assert_eq!(FileRepository::open(&path.0)?.version_of(1)?, 3);
+        repo.compact()?;
+        assert_eq!(FileRepository::open(&path.0)?.version_of(1)?, 3);
+
+        Ok(())
     }
 
     #[test]
-    fn test_repository() -> Result<()> {
+    fn test_versions_survive_delete() -> Result<()> {
         let mut repo = InMemoryRepository::new();
-        let user = User::new(1, "Test", "test@example.com");
-        
-        repo.save(user.clone())?;
-        
-        let found = repo.find_by_id(1)?;
-        assert!(found.is_some());
-        assert_eq!(found.unwrap().name, "Test");
-        
+        let user = |name| User::new(1, name, "a@example.com");
+        repo.save(user("A"))?;
+
+        // An editor read version 1, then the record was deleted and recreated
+        repo.delete(1)?;
+        assert_eq!(repo.version_of(1)?, 2);
+        assert_eq!(repo.save_if_version(user("B"), 2)?, 3);
+
+        let stale = repo.save_if_version(user("C"), 1);
+        assert!(matches!(stale, Err(AppError::VersionConflict { .. })));
+        assert_eq!(repo.find_by_id(1)?.unwrap().name, "B");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sqlite_repository_versions() -> Result<()> {
+        let mut repo = SqliteRepository::open_in_memory()?;
+        let user = |name| User::new(1, name, "a@example.com");
+        assert_eq!(repo.save_if_version(user("A"), 0)?, 1);
+        repo.save(user("B"))?;
+        assert_eq!(repo.version_of(1)?, 2);
+
+        let stale = repo.save_if_version(user("C"), 1);
+        assert!(matches!(
+            stale,
+            Err(AppError::VersionConflict {
+                expected: 1,
+                actual: 2,
+                ..
+            })
+        ));
+        assert_eq!(repo.find_by_id(1)?.unwrap().name, "B");
+
+        repo.delete(1)?;
+        assert_eq!(repo.version_of(1)?, 3);
+        assert_eq!(repo.save_if_version(user("D"), 3)?, 4);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_shared_repository_clones_share_state() -> Result<()> {
+        let repo = SharedRepository::new();
+        let handle = repo.clone();
+
+        handle.save(User::new(1, "A", "a@example.com")).await?;
+        assert_eq!(repo.find_by_id(1).await?.unwrap().name, "A");
+        assert_eq!(repo.version_of(1).await?, 1);
+        assert_eq!(repo.len().await?, 1);
+
+        assert!(repo.delete(1).await?);
+        assert!(handle.is_empty().await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_shared_repository_reports_poisoning() {
+        let repo = SharedRepository::<User>::new();
+        let poisoner = repo.clone();
+        let _ = std::thread::spawn(move || {
+            let _guard = poisoner.inner.write().unwrap();
+            panic!("poison the lock");
+        })
+        .join();
+
+        let result = repo.find_by_id(1).await;
+        assert!(matches!(result, Err(AppError::Internal(_))));
+    }
+
+    #[test]
+    fn test_user_cache_expires_entries() -> Result<()> {
+        let clock = ManualClock::new();
+        let cache = UserCache::new(10, Duration::from_secs(60)).with_clock(clock.clone());
+        cache.insert(User::new(1, "A", "a@example.com"))?;
+
+        clock.advance(Duration::from_secs(59));
+        assert!(cache.get(1)?.is_some());
+        clock.advance(Duration::from_secs(1));
+        assert!(cache.get(1)?.is_none());
+        assert!(cache.is_empty()?);
+
+        let stats = cache.stats()?;
+        assert_eq!((stats.hits, stats.misses, stats.evictions), (1, 1, 0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_user_cache_evicts_least_recently_used() -> Result<()> {
+        let cache = UserCache::new(2, Duration::from_secs(60)).with_clock(ManualClock::new());
+        cache.insert(User::new(1, "A", "a@example.com"))?;
+        cache.insert(User::new(2, "B", "b@example.com"))?;
+
+        // Touching 1 leaves 2 as the eviction candidate
+        assert!(cache.get(1)?.is_some());
+        cache.insert(User::new(3, "C", "c@example.com"))?;
+        assert!(cache.get(2)?.is_none());
+        assert!(cache.get(1)?.is_some());
+        assert_eq!(cache.stats()?.evictions, 1);
+
+        assert!(cache.invalidate(1)?);
+        assert!(!cache.invalidate(1)?);
+        cache.clear()?;
+        assert!(cache.is_empty()?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_user_cache_drops_expired_before_evicting() -> Result<()> {
+        let clock = ManualClock::new();
+        let cache = UserCache::new(2, Duration::from_secs(60)).with_clock(clock.clone());
+        cache.insert(User::new(1, "A", "a@example.com"))?;
+        clock.advance(Duration::from_secs(30));
+        cache.insert(User::new(2, "B", "b@example.com"))?;
+
+        // 1 has expired, so it makes room without counting as an eviction
+        clock.advance(Duration::from_secs(31));
+        cache.insert(User::new(3, "C", "c@example.com"))?;
+        assert_eq!(cache.len()?, 2);
+        assert_eq!(cache.stats()?.evictions, 0);
+
+        // Re-inserting an id moves it to the back of the eviction order
+        cache.insert(User::new(2, "B2", "b@example.com"))?;
+        cache.insert(User::new(4, "D", "d@example.com"))?;
+        assert!(cache.get(3)?.is_none());
+        assert_eq!(cache.get(2)?.unwrap().name, "B2");
+        assert_eq!(cache.stats()?.evictions, 1);
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_fetch_user_coalesces_concurrent_misses() -> Result<()> {
+        let source = MockUserSource::new()
+            .with_user(User::new(1, "Test", "test@example.com"))
+            .with_delay(Duration::from_millis(50));
+        let cache = UserCache::default();
+
+        let (a, b, c) = tokio::join!(
+            fetch_user(&cache, &source, 1),
+            fetch_user(&cache, &source, 1),
+            fetch_user(&cache, &source, 1),
+        );
+        for result in [a, b, c] {
+            assert_eq!(result?.unwrap().name, "Test");
+        }
+        assert_eq!(source.calls(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_fetch_user_shares_errors_between_waiters() {
+        let source = MockUserSource::new()
+            .failing("backend down")
+            .with_delay(Duration::from_millis(50));
+        let cache = UserCache::default();
+
+        let (a, b) = tokio::join!(
+            fetch_user(&cache, &source, 1),
+            fetch_user(&cache, &source, 1),
+        );
+        assert!(matches!(a, Err(AppError::NetworkError(_))));
+        assert!(matches!(b, Err(AppError::NetworkError(_))));
+        assert_eq!(source.calls(), 1);
+
+        // A settled failure is not remembered, so the next call tries again
+        assert!(fetch_user(&cache, &source, 1).await.is_err());
+        assert_eq!(source.calls(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_user_remembers_missing_ids() -> Result<()> {
+        let clock = ManualClock::new();
+        let source = MockUserSource::new();
+        let cache = UserCache::default()
+            .with_negative_ttl(Duration::from_secs(5))
+            .with_clock(clock.clone());
+
+        assert!(fetch_user(&cache, &source, 1).await?.is_none());
+        assert!(fetch_user(&cache, &source, 1).await?.is_none());
+        assert_eq!(source.calls(), 1);
+
+        clock.advance(Duration::from_secs(5));
+        assert!(fetch_user(&cache, &source, 1).await?.is_none());
+        assert_eq!(source.calls(), 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cached_repository_replaces_negative_entry() -> Result<()> {
+        let cache = UserCache::default().with_negative_ttl(Duration::from_secs(5));
+        let mut repo = CachedRepository::new(InMemoryRepository::new(), cache.clone());
+
+        assert!(fetch_user(&cache, repo.inner(), 1).await?.is_none());
+        repo.save(User::new(1, "Test", "test@example.com"))?;
+        let user = fetch_user(&cache, repo.inner(), 1).await?;
+        assert_eq!(user.unwrap().name, "Test");
+
+        // The cached copy goes with the delete
+        repo.delete(1)?;
+        assert!(fetch_user(&cache, repo.inner(), 1).await?.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cached_shared_repository_keeps_cache_in_step() -> Result<()> {
+        let cache = UserCache::default().with_negative_ttl(Duration::from_secs(5));
+        let repo = CachedRepository::new(SharedRepository::new(), cache.clone());
+
+        assert!(fetch_user(&cache, repo.inner(), 1).await?.is_none());
+        repo.save(User::new(1, "Test", "test@example.com")).await?;
+        let user = fetch_user(&cache, repo.inner(), 1).await?;
+        assert_eq!(user.unwrap().name, "Test");
+
+        let renamed = User::new(1, "Renamed", "test@example.com");
+        repo.save_if_version(renamed, 1).await?;
+        let user = fetch_user(&cache, repo.inner(), 1).await?;
+        assert_eq!(user.unwrap().name, "Renamed");
+
+        repo.delete(1).await?;
+        assert!(fetch_user(&cache, repo.inner(), 1).await?.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fetch_users_mixes_hits_and_misses() -> Result<()> {
+        let source = MockUserSource::new().with_user(User::new(2, "B", "b@example.com"));
+        let cache = UserCache::default();
+        cache.insert(User::new(1, "A", "a@example.com"))?;
+
+        let batch = fetch_users(&cache, &source, &[3, 1, 2, 2]).await?;
+        assert_eq!(batch.users[&1].name, "A");
+        assert_eq!(batch.users[&2].name, "B");
+        assert_eq!(batch.missing, vec![3]);
+        assert!(batch.errors.is_empty());
+        assert_eq!(source.calls(), 2);
+
+        // Everything fetched is cached now
+        fetch_users(&cache, &source, &[1, 2]).await?;
+        assert_eq!(source.calls(), 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fetch_users_reports_failures_per_id() -> Result<()> {
+        let source = MockUserSource::new().failing("backend down");
+        let cache = UserCache::default();
+        cache.insert(User::new(1, "A", "a@example.com"))?;
+
+        let batch = fetch_users(&cache, &source, &[1, 2]).await?;
+        assert_eq!(batch.users.len(), 1);
+        assert!(matches!(batch.errors[&2], AppError::NetworkError(_)));
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_fetch_users_shares_fetches_with_fetch_user() -> Result<()> {
+        let source = MockUserSource::new()
+            .with_user(User::new(1, "Alice", "alice@example.com"))
+            .with_user(User::new(2, "Bob", "bob@example.com"))
+            .with_delay(Duration::from_secs(1));
+
+        // Whichever call starts first, each id reaches the backend once
+        let cache = UserCache::default();
+        let (single, batch) = tokio::join!(
+            fetch_user(&cache, &source, 1),
+            fetch_users(&cache, &source, &[1, 2])
+        );
+        assert_eq!(single?.unwrap().name, "Alice");
+        assert_eq!(batch?.users.len(), 2);
+        assert_eq!(source.calls(), 2);
+
+        let cache = UserCache::default();
+        let (batch, single) = tokio::join!(
+            fetch_users(&cache, &source, &[1, 2]),
+            fetch_user(&cache, &source, 2)
+        );
+        assert_eq!(batch?.users.len(), 2);
+        assert_eq!(single?.unwrap().name, "Bob");
+        assert_eq!(source.calls(), 4);
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_fetch_users_serves_users_being_refreshed() -> Result<()> {
+        let clock = ManualClock::new();
+        let source = Arc::new(
+            MockUserSource::new()
+                .with_user(User::new(1, "Alice", "alice@example.com"))
+                .with_delay(Duration::from_secs(1)),
+        );
+        let cache = UserCache::new(10, Duration::from_secs(60))
+            .with_stale_while_revalidate(Duration::from_secs(60))
+            .with_clock(clock.clone());
+
+        fetch_user_shared(&cache, &source, 1).await?;
+        clock.advance(Duration::from_secs(61));
+        fetch_user_shared(&cache, &source, 1).await?;
+        tokio::task::yield_now().await;
+
+        // The background refresh is still running, so the batch takes the stale copy
+        let batch = fetch_users(&cache, &*source, &[1]).await?;
+        assert_eq!(batch.users[&1].name, "Alice");
+        assert_eq!(source.calls(), 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fetch_users_over_http_in_parallel() -> Result<()> {
+        let server = MockServer::start(vec![(404, ""); 3]);
+        let client = server.client().with_max_concurrency(2);
+
+        let batch = fetch_users(&UserCache::default(), &client, &[1, 2, 3]).await?;
+        assert_eq!(batch.missing, vec![1, 2, 3]);
+        assert_eq!(server.hits(), 3);
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_get_user_times_out_over_http() {
+        let server = MockServer::start(vec![(0, ""), (0, "")]);
+        let client = server
+            .client()
+            .with_timeout(Duration::from_secs(1))
+            .with_max_retries(1);
+
+        let result = client.get_user(1).await;
+        assert!(matches!(result, Err(AppError::Timeout(_))));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_get_user_deadline_spans_retries_over_http() {
+        let server = MockServer::start(vec![(0, ""); 4]);
+        let client = server
+            .client()
+            .with_timeout(Duration::from_secs(5))
+            .with_deadline(Duration::from_secs(8));
+
+        let started = Instant::now();
+        let result = client.get_user(1).await;
+        assert!(matches!(result, Err(AppError::Timeout(_))));
+        assert!(started.elapsed() < Duration::from_secs(9));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_cancelled_fetch_leaves_no_cache_entry() -> Result<()> {
+        let source = MockUserSource::new()
+            .with_user(User::new(1, "Test", "test@example.com"))
+            .with_delay(Duration::from_secs(10));
+        let cache = UserCache::default();
+
+        let cancelled =
+            tokio::time::timeout(Duration::from_secs(1), fetch_user(&cache, &source, 1)).await;
+        assert!(cancelled.is_err());
+        assert!(cache.is_empty()?);
+
+        // The abandoned fetch is forgotten, so the next call starts a fresh one
+        assert!(fetch_user(&cache, &source, 1).await?.is_some());
+        assert_eq!(source.calls(), 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_circuit_breaker_opens_and_fails_fast() -> Result<()> {
+        let clock = ManualClock::new();
+        let transitions = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&transitions);
+        let source = MockUserSource::new().failing("backend down");
+        let breaker = CircuitBreaker::new(source.clone())
+            .with_failure_threshold(2)
+            .with_cooldown(Duration::from_secs(30))
+            .with_clock(clock.clone())
+            .on_transition(move |from, to| seen.lock().unwrap().push((from, to)));
+        let cache = UserCache::default();
+        cache.insert(User::new(1, "Cached", "cached@example.com"))?;
+
+        for _ in 0..2 {
+            assert!(fetch_user(&cache, &breaker, 2).await.is_err());
+        }
+        assert_eq!(breaker.state()?, CircuitState::Open);
+
+        // Open: misses fail fast without reaching the backend, hits still work
+        match fetch_user(&cache, &breaker, 2).await {
+            Err(AppError::NetworkError(message)) => assert_eq!(message, "circuit open"),
+            other => panic!("expected the circuit to be open, got {:?}", other),
+        }
+        assert_eq!(source.calls(), 2);
+        let cached = fetch_user(&cache, &breaker, 1).await?;
+        assert_eq!(cached.unwrap().name, "Cached");
+
+        // After the cool-down a failing probe reopens the circuit
+        clock.advance(Duration::from_secs(30));
+        assert!(fetch_user(&cache, &breaker, 2).await.is_err());
+        assert_eq!(source.calls(), 3);
+        assert_eq!(breaker.state()?, CircuitState::Open);
+
+        use CircuitState::*;
+        let expected = vec![(Closed, Open), (Open, HalfOpen), (HalfOpen, Open)];
+        assert_eq!(*transitions.lock().unwrap(), expected);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_circuit_breaker_closes_after_successful_probe() -> Result<()> {
+        let clock = ManualClock::new();
+        let source = MockUserSource::new()
+            .with_user(User::new(1, "Test", "test@example.com"))
+            .failing_first(2);
+        let breaker = CircuitBreaker::new(source)
+            .with_failure_threshold(2)
+            .with_clock(clock.clone());
+
+        assert!(breaker.fetch(1).await.is_err());
+        assert!(breaker.fetch(1).await.is_err());
+        assert_eq!(breaker.state()?, CircuitState::Open);
+
+        clock.advance(BREAKER_COOLDOWN);
+        assert!(breaker.fetch(1).await?.is_some());
+        assert_eq!(breaker.state()?, CircuitState::Closed);
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_fetch_user_deadline_bounds_any_source() -> Result<()> {
+        let source = MockUserSource::new()
+            .with_user(User::new(1, "Slow", "slow@example.com"))
+            .with_delay(Duration::from_secs(60));
+        let cache = UserCache::default().with_fetch_deadline(Duration::from_secs(2));
+
+        let started = Instant::now();
+        let result = fetch_user(&cache, &source, 1).await;
+        assert!(matches!(result, Err(AppError::Timeout(_))));
+        assert!(started.elapsed() < Duration::from_secs(3));
+
+        // Batches are bounded the same way, with a timeout per missing id
+        let breaker = CircuitBreaker::new(source);
+        let batch = fetch_users(&cache, &breaker, &[1, 2]).await?;
+        assert!(batch.users.is_empty());
+        assert_eq!(batch.errors.len(), 2);
+        for err in batch.errors.values() {
+            assert!(matches!(err, AppError::Timeout(_)));
+        }
+        assert!(cache.is_empty()?);
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_circuit_breaker_counts_timed_out_calls() -> Result<()> {
+        let source = MockUserSource::new()
+            .with_user(User::new(1, "Slow", "slow@example.com"))
+            .with_delay(Duration::from_secs(60));
+        let breaker = CircuitBreaker::new(source.clone()).with_failure_threshold(1);
+        let cache = UserCache::default().with_fetch_deadline(Duration::from_secs(1));
+
+        // A backend that hangs trips the breaker just like one that errors
+        let result = fetch_user(&cache, &breaker, 1).await;
+        assert!(matches!(result, Err(AppError::Timeout(_))));
+        assert_eq!(breaker.state()?, CircuitState::Open);
+
+        let result = fetch_user(&cache, &breaker, 1).await;
+        assert!(matches!(result, Err(AppError::NetworkError(_))));
+        assert_eq!(source.calls(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_circuit_breaker_keeps_batches_parallel() {
+        let server = MockServer::start(vec![(0, ""); 3]);
+        let client = server
+            .client()
+            .with_timeout(Duration::from_secs(1))
+            .with_max_retries(0)
+            .with_max_concurrency(3);
+        let breaker = CircuitBreaker::new(client).with_failure_threshold(1);
+
+        // All three requests time out together rather than one after another
+        let started = Instant::now();
+        let results = breaker.fetch_many(&[1, 2, 3]).await;
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert!(results.values().all(|result| result.is_err()));
+        assert_eq!(breaker.state().unwrap(), CircuitState::Open);
+
+        // Once open, a batch fails fast without reaching the server
+        let results = breaker.fetch_many(&[1, 2]).await;
+        assert_eq!(results.len(), 2);
+        for result in results.values() {
+            match result {
+                Err(AppError::NetworkError(message)) => assert_eq!(message, "circuit open"),
+                other => panic!("expected the circuit to be open, got {:?}", other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stale_while_revalidate_refreshes_in_background() -> Result<()> {
+        let clock = ManualClock::new();
+        let source = Arc::new(SharedRepository::new());
+        source.save(User::new(1, "Old", "user@example.com")).await?;
+        let cache = UserCache::new(10, Duration::from_secs(60))
+            .with_stale_while_revalidate(Duration::from_secs(60))
+            .with_clock(clock.clone());
+
+        fetch_user_shared(&cache, &source, 1).await?;
+        source.save(User::new(1, "New", "user@example.com")).await?;
+        clock.advance(Duration::from_secs(61));
+
+        // The stale copy comes back at once while the refresh runs
+        let user = fetch_user_shared(&cache, &source, 1).await?;
+        assert_eq!(user.unwrap().name, "Old");
+        tokio::task::yield_now().await;
+        assert_eq!(cache.get(1)?.unwrap().name, "New");
+
+        // Past the grace period the entry is gone and the caller waits again
+        clock.advance(Duration::from_secs(121));
+        assert!(cache.get(1)?.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fetch_user_refreshes_stale_users_from_its_own_source() -> Result<()> {
+        let clock = ManualClock::new();
+        let source = SharedRepository::new();
+        source.save(User::new(1, "Old", "user@example.com")).await?;
+        let cache = UserCache::new(10, Duration::from_secs(60))
+            .with_stale_while_revalidate(Duration::from_secs(60))
+            .with_clock(clock.clone());
+
+        fetch_user(&cache, &source, 1).await?;
+        clock.advance(Duration::from_secs(61));
+
+        // Without a shared source the refresh runs inline, through the source given
+        let breaker = CircuitBreaker::new(MockUserSource::new().failing("backend down"));
+        assert!(fetch_user(&cache, &breaker, 1).await.is_err());
+        assert_eq!(cache.len()?, 1);
+
+        source.save(User::new(1, "New", "user@example.com")).await?;
+        assert_eq!(fetch_user(&cache, &source, 1).await?.unwrap().name, "New");
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stale_while_revalidate_runs_one_refresh_per_id() -> Result<()> {
+        let clock = ManualClock::new();
+        let source = MockUserSource::new()
+            .with_user(User::new(1, "Test", "test@example.com"))
+            .with_delay(Duration::from_secs(1));
+        let shared = Arc::new(source.clone());
+        let failures = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&failures);
+        let cache = UserCache::new(10, Duration::from_secs(60))
+            .with_stale_while_revalidate(Duration::from_secs(600))
+            .with_clock(clock.clone())
+            .on_refresh_error(move |id, err| seen.lock().unwrap().push((id, err.code())));
+
+        fetch_user_shared(&cache, &shared, 1).await?;
+        clock.advance(Duration::from_secs(61));
+        let _ = source.clone().failing_first(1);
+
+        // The second caller finds the first refresh still running and starts none
+        assert!(fetch_user_shared(&cache, &shared, 1).await?.is_some());
+        tokio::task::yield_now().await;
+        assert!(fetch_user_shared(&cache, &shared, 1).await?.is_some());
+        assert_eq!(source.calls(), 2);
+
+        // The failed refresh is reported, the stale copy stays and the next read retries
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(*failures.lock().unwrap(), vec![(1, "E003")]);
+        assert!(fetch_user_shared(&cache, &shared, 1).await?.is_some());
+        tokio::task::yield_now().await;
+        assert_eq!(source.calls(), 3);
+
         Ok(())
     }
 }